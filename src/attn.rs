@@ -1,25 +1,26 @@
 use std::ops::Add;
 
-use crate::{
-    attn_head::AttnHead,
-    input::{InputData, InputSeq, PositionedEmbeddings},
-};
-use candle_core::{quantized::QTensor, Device, Shape, Tensor};
-use itertools::Itertools;
+use crate::attn_head::{AttnHead, NeuralNet};
+use crate::error::{BartError, Result};
+use candle_core::{quantized::QTensor, DType, Device, Shape, Tensor, D};
 use tracing::debug;
 
+/// BART Large splits its 1024 hidden dimensions into 16 heads of 64 dimensions each
+pub const BART_NUM_HEADS: usize = 16;
+pub const BART_HEAD_DIM: usize = 64;
+
 pub struct Encoded {
     q: Tensor,
     k: Tensor,
     v: Tensor,
 }
 
-impl InputData for Encoded {}
 impl Encoded {
     pub fn new(q: Tensor, k: Tensor, v: Tensor) -> Self {
         Self { q, k, v }
     }
 }
+
 /// This function is needed to reshape the 1D bias tensor into a 2D tensor
 /// where each row corresponds to a row in the input tensor. The purpose of this is to
 /// perform an element-wise addition (or broadcasted operation) between the input tensor and the bias tensor.
@@ -38,50 +39,266 @@ fn stack_1d_tensor(
     Ok(full_bias)
 }
 
+impl NeuralNet {
+    /// Applies the linear layer `input * weights^T + bias` in f16 to every row of every batch
+    pub fn forward(&self, input: &Tensor, device: &Device) -> Result<Tensor> {
+        // The weights are stored as (out_features, in_features)
+        let (_, in_features) = self.weights.shape().dims2()?;
+        let input_features = input.dim(D::Minus1)?;
+        if input_features != in_features {
+            return Err(BartError::ShapeMismatch(format!(
+                "input {:?} has {input_features} features but the layer takes {in_features}",
+                input.shape()
+            )));
+        }
+        let input = input.to_dtype(DType::F16)?;
+        debug!(
+            "multiplying input {:?} with weights {:?}",
+            input.shape(),
+            self.weights.shape()
+        );
+        let tensor = input.broadcast_matmul(&self.weights.dequantize_f16(device)?.t()?)?;
+        let bias = stack_1d_tensor(tensor.shape(), &self.bias, device)?;
+
+        debug!(
+            "adding bias {:?} to matmul {:?}",
+            bias.shape(),
+            tensor.shape()
+        );
+        Ok(tensor.add(&bias)?)
+    }
+}
+
 impl AttnHead {
-    fn encode(
+    /// Projects the hidden states into queries, keys and values
+    fn project(&self, hidden: &Tensor, device: &Device) -> Result<Encoded> {
+        // Perform matrix multiplication and add bias for each of q, k, v
+        let [q, k, v] =
+            [self.get_q(), self.get_k(), self.get_v()].map(|x| x.forward(hidden, device));
+        Ok(Encoded::new(q?, k?, v?))
+    }
+
+    /// Applies the output projection to the concatenated heads and adds the residual
+    fn output(&self, hidden: &Tensor, attn: &Tensor, device: &Device) -> Result<Tensor> {
+        let out = self.get_out().forward(attn, device)?;
+        Ok((hidden + out.to_dtype(hidden.dtype())?)?)
+    }
+
+    /// The full self-attention sub-block: attention over `hidden`, the output projection
+    /// and the residual connection. The result has the same shape and dtype as `hidden`.
+    pub fn forward(
         &self,
-        input: InputSeq<PositionedEmbeddings>,
+        hidden: &Tensor,
+        attention_mask: Option<&Tensor>,
         device: &Device,
-    ) -> candle_core::Result<InputSeq<Encoded>> {
-        let input_embeds = input.get_embeds().to_dtype(candle_core::DType::F16)?;
+    ) -> Result<Tensor> {
+        let Encoded { q, k, v } = self.project(hidden, device)?;
+        let attn = scaled_dot_product_attention(&q, &k, &v, attention_mask, false)?;
+        self.output(hidden, &attn, device)
+    }
 
-        // Perform matrix multiplication and add bias for each of q, k, v
-        let (q, k, v) = [self.get_q(), self.get_k(), self.get_v()]
-            .map(|x| {
-                debug!(
-                    "multiplying input embeds {:?} with weights {:?}",
-                    input_embeds.shape(),
-                    x.weights.shape()
-                );
-                let tensor = input_embeds.matmul(&x.weights.dequantize_f16(device)?)?;
-                let bias = stack_1d_tensor(tensor.shape(), &x.bias, device)?;
-
-                println!(
-                    "adding bias {:?} to matmul {:?}",
-                    bias.shape(),
-                    tensor.shape()
-                );
-                tensor.add(&bias)
-                //TODO: add bias to every row of 2D matmul result
-            })
-            .into_iter()
-            .collect_tuple()
-            .unwrap();
+    /// Like [`AttnHead::forward`], but every position may only attend to itself and
+    /// the positions before it, as the decoder must not see the tokens it has yet to generate.
+    /// `hidden` holds only the new positions; the keys and values of earlier positions are
+    /// read from `cache`, and those of the new positions are appended to it.
+    pub fn forward_causal(
+        &self,
+        hidden: &Tensor,
+        cache: &mut KvCache,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let Encoded { q, k, v } = self.project(hidden, device)?;
+        let (k, v) = match cache.get() {
+            Some((past_k, past_v)) => (
+                Tensor::cat(&[past_k, &k], 1)?,
+                Tensor::cat(&[past_v, &v], 1)?,
+            ),
+            None => (k, v),
+        };
+        let attn = scaled_dot_product_attention(&q, &k, &v, None, true)?;
+        cache.kv = Some((k, v));
+        self.output(hidden, &attn, device)
+    }
 
-        Ok(InputSeq::<Encoded> {
-            state: Encoded::new(q?, k?, v?),
-        })
+    /// Cross-attention: the queries come from the decoder's `hidden` states while
+    /// the keys and values come from the encoder's output. The encoder's output doesn't
+    /// change while decoding, so its keys and values are only projected once and then
+    /// reused from `cache`.
+    pub fn forward_cross(
+        &self,
+        hidden: &Tensor,
+        encoder_hidden: &Tensor,
+        encoder_attention_mask: Option<&Tensor>,
+        cache: &mut KvCache,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let q = self.get_q().forward(hidden, device)?;
+        let (k, v) = match &mut cache.kv {
+            Some(kv) => kv,
+            None => {
+                debug!("Projecting encoder output into cross-attention keys and values");
+                let k = self.get_k().forward(encoder_hidden, device)?;
+                let v = self.get_v().forward(encoder_hidden, device)?;
+                cache.kv.insert((k, v))
+            }
+        };
+        let attn = scaled_dot_product_attention(&q, k, v, encoder_attention_mask, false)?;
+        self.output(hidden, &attn, device)
     }
 }
 
+/// The projected keys and values of the positions an attention block has already seen,
+/// so that incremental decoding only has to project the newest token
+#[derive(Clone, Default)]
+pub struct KvCache {
+    kv: Option<(Tensor, Tensor)>,
+}
+
+impl KvCache {
+    pub fn get(&self) -> Option<(&Tensor, &Tensor)> {
+        self.kv.as_ref().map(|(k, v)| (k, v))
+    }
+
+    /// The number of cached positions
+    pub fn len(&self) -> Result<usize> {
+        match &self.kv {
+            Some((k, _)) => Ok(k.dim(1)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Rearranges the cached batch rows so that row `i` continues from row `indices[i]`,
+    /// as beam search does when a beam is extended from another beam
+    pub fn reorder(&mut self, indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv {
+            self.kv = Some((k.index_select(indices, 0)?, v.index_select(indices, 0)?));
+        }
+        Ok(())
+    }
+}
+
+/// Splits a `(batch, seq_len, hidden)` tensor into `(batch, num_heads, seq_len, head_dim)`
+fn split_heads(tensor: &Tensor) -> candle_core::Result<Tensor> {
+    let (batch_size, seq_len, _) = tensor.dims3()?;
+    tensor
+        .reshape((batch_size, seq_len, BART_NUM_HEADS, BART_HEAD_DIM))?
+        .transpose(1, 2)?
+        .contiguous()
+}
+
+/// Concatenates `(batch, num_heads, seq_len, head_dim)` back into `(batch, seq_len, hidden)`
+fn merge_heads(tensor: &Tensor) -> candle_core::Result<Tensor> {
+    let (batch_size, _, seq_len, _) = tensor.dims4()?;
+    tensor.transpose(1, 2)?.contiguous()?.reshape((
+        batch_size,
+        seq_len,
+        BART_NUM_HEADS * BART_HEAD_DIM,
+    ))
+}
+
+/// Turns a `(batch, seq_len)` attention mask, where 1 marks a real token and 0 marks padding,
+/// into an additive mask that can be broadcast over the `(batch, num_heads, q_len, k_len)` scores.
+fn additive_mask(attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+    let (batch_size, k_len) = attention_mask.dims2()?;
+    let shape = (batch_size, k_len);
+    let zeros = Tensor::zeros(shape, DType::F32, attention_mask.device())?;
+    let min = Tensor::full(f32::MIN, shape, attention_mask.device())?;
+    attention_mask
+        .ne(0u8)?
+        .where_cond(&zeros, &min)?
+        .reshape((batch_size, 1, 1, k_len))
+}
+
+/// An additive `(q_len, k_len)` mask hiding every key that comes after the query.
+/// The queries are taken to be the last `q_len` positions of the keys.
+fn causal_mask(q_len: usize, k_len: usize, device: &Device) -> candle_core::Result<Tensor> {
+    let offset = k_len - q_len;
+    let mask: Vec<f32> = (0..q_len)
+        .flat_map(|i| (0..k_len).map(move |j| if j > i + offset { f32::MIN } else { 0.0 }))
+        .collect();
+    Tensor::from_vec(mask, (q_len, k_len), device)
+}
+
+/// Numerically stable softmax over the last dimension
+fn softmax_last_dim(tensor: &Tensor) -> candle_core::Result<Tensor> {
+    let max = tensor.max_keepdim(D::Minus1)?;
+    let exp = tensor.broadcast_sub(&max)?.exp()?;
+    let sum = exp.sum_keepdim(D::Minus1)?;
+    exp.broadcast_div(&sum)
+}
+
+/// Multi-head scaled dot-product attention, `softmax(q k^T / sqrt(d_head)) v`.
+/// `q`, `k` and `v` are the projected `(batch, seq_len, hidden)` tensors. The scores are
+/// computed in f32 since f16 overflows in the softmax, and the result is returned
+/// in the dtype of `q` with the heads concatenated back into `(batch, q_len, hidden)`.
+/// When `causal` is set, queries cannot attend to keys that come after them.
+pub fn scaled_dot_product_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    attention_mask: Option<&Tensor>,
+    causal: bool,
+) -> Result<Tensor> {
+    let hidden_size = BART_NUM_HEADS * BART_HEAD_DIM;
+    for (name, x) in [("queries", q), ("keys", k), ("values", v)] {
+        let (_, _, dim) = x.dims3()?;
+        if dim != hidden_size {
+            return Err(BartError::ShapeMismatch(format!(
+                "{name} {:?} don't split into {BART_NUM_HEADS} heads of {BART_HEAD_DIM}",
+                x.shape()
+            )));
+        }
+    }
+    if k.shape() != v.shape() {
+        return Err(BartError::ShapeMismatch(format!(
+            "keys {:?} and values {:?} differ in shape",
+            k.shape(),
+            v.shape()
+        )));
+    }
+    if let Some(attention_mask) = attention_mask {
+        let (batch_size, k_len, _) = k.dims3()?;
+        if attention_mask.dims() != [batch_size, k_len] {
+            return Err(BartError::ShapeMismatch(format!(
+                "attention mask {:?} doesn't match keys {:?}",
+                attention_mask.shape(),
+                k.shape()
+            )));
+        }
+    }
+    let dtype = q.dtype();
+    let [q, k, v] = [q, k, v].map(|x| split_heads(&x.to_dtype(DType::F32)?));
+    let (q, k, v) = (q?, k?, v?);
+
+    let scaling = 1.0 / (BART_HEAD_DIM as f64).sqrt();
+    let q = (q * scaling)?;
+    let mut scores = q.matmul(&k.t()?)?;
+    debug!("attention scores {:?}", scores.shape());
+    if let Some(attention_mask) = attention_mask {
+        scores = scores.broadcast_add(&additive_mask(attention_mask)?)?;
+    }
+    if causal {
+        let (_, _, q_len, k_len) = scores.dims4()?;
+        scores = scores.broadcast_add(&causal_mask(q_len, k_len, scores.device())?)?;
+    }
+    let probs = softmax_last_dim(&scores)?;
+    let attn = probs.matmul(&v)?;
+
+    Ok(merge_heads(&attn)?.to_dtype(dtype)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bart_tensor_type::{
+        AttnBlock, AttnLayer, AttnType, OutProjLayer, Stack, TensorName, TensorType,
+    };
+    use crate::input::{InputSeq, Truncation};
+    use crate::tensors::WeightSource;
+    use crate::tokenizer::WordPieceTokenizer;
     use crate::utils::assertions;
     use crate::utils::assertions::Print;
-    use crate::TensorName;
-    use crate::WordPieceTokenizer;
+    use candle_core::quantized::GgmlDType;
     use candle_core::Shape;
     use candle_core::{Device, Tensor};
     use half::f16;
@@ -231,20 +448,157 @@ mod tests {
         let device = candle_core::Device::new_metal(0).unwrap();
         let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
 
-        let token_embeds = tensors.get_tensor(TensorName::EmbedTokensWeights, &device);
-        let pos_embeds = tensors.get_tensor(TensorName::EmbedPositionWeights, &device);
+        let token_embeds = tensors
+            .get_tensor(TensorName::EmbedTokensWeights, &device)
+            .unwrap();
+        let pos_embeds = tensors
+            .get_tensor(TensorName::EmbedPositionWeights(Stack::Encoder), &device)
+            .unwrap();
+        let layernorm_embedding = crate::layer_norm::LayerNorm::new(
+            |t| TensorName::LayerNormEmbedding(Stack::Encoder, t),
+            &mut tensors,
+            &device,
+        )
+        .unwrap();
         let input_seq = InputSeq::new("The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration".into());
         let input_seq = input_seq
             .tokenize(&tokenizer)
-            .format_for_bart()
+            .format_for_bart(Truncation::LongestFirst)
+            .unwrap()
+            .into_batch()
             .embed(&token_embeds.dequantize(&device).unwrap())
             .unwrap()
-            .add_pos_embeds(&pos_embeds.dequantize(&device).unwrap())
+            .add_pos_embeds(
+                &pos_embeds.dequantize(&device).unwrap(),
+                0,
+                &layernorm_embedding,
+            )
             .unwrap();
+        let mask = input_seq.get_attention_mask();
 
         for i in 0..12 {
-            let attn_head = AttnHead::new(i, &mut tensors, &device).unwrap();
-            attn_head.encode(input_seq.clone(), &device).unwrap();
+            let attn_head = AttnHead::new(
+                Stack::Encoder,
+                AttnBlock::SelfAttn,
+                i,
+                &mut tensors,
+                &device,
+            )
+            .unwrap();
+            let hidden = attn_head
+                .forward(input_seq.get_embeds(), Some(mask), &device)
+                .unwrap();
+            assert_eq!(hidden.shape(), input_seq.get_embeds().shape());
+        }
+    }
+
+    /// The weights of an attention block whose queries and keys are all zero, so that every
+    /// position attends evenly to the unmasked ones, and whose value and output projections
+    /// are the identity
+    struct AveragingWeights;
+
+    impl WeightSource for AveragingWeights {
+        fn get_tensor(
+            &mut self,
+            tensor_name: TensorName,
+            device: &Device,
+        ) -> crate::error::Result<QTensor> {
+            let hidden = BART_NUM_HEADS * BART_HEAD_DIM;
+            let tensor = match tensor_name {
+                TensorName::Attn(AttnLayer {
+                    attn_type: AttnType::Value,
+                    tensor_type: TensorType::Weight,
+                    ..
+                })
+                | TensorName::OutProj(OutProjLayer {
+                    tensor_type: TensorType::Weight,
+                    ..
+                }) => Tensor::eye(hidden, DType::F32, device)?,
+                TensorName::Attn(AttnLayer {
+                    tensor_type: TensorType::Weight,
+                    ..
+                }) => Tensor::zeros((hidden, hidden), DType::F32, device)?,
+                _ => Tensor::zeros(hidden, DType::F32, device)?,
+            };
+            Ok(QTensor::quantize(&tensor, GgmlDType::F16)?)
+        }
+    }
+
+    #[test]
+    fn forward_averages_unmasked_values() {
+        let device = Device::Cpu;
+        let attn_head = AttnHead::new(
+            Stack::Encoder,
+            AttnBlock::SelfAttn,
+            0,
+            &mut AveragingWeights,
+            &device,
+        )
+        .unwrap();
+        let hidden = Tensor::new(&[1f32, 2., 3.], &device)
+            .unwrap()
+            .reshape((1, 3, 1))
+            .unwrap()
+            .broadcast_as((1, 3, BART_NUM_HEADS * BART_HEAD_DIM))
+            .unwrap()
+            .contiguous()
+            .unwrap();
+        let mask = Tensor::new(&[[1u8, 1, 0]], &device).unwrap();
+
+        let out = attn_head.forward(&hidden, Some(&mask), &device).unwrap();
+        assert_eq!(out.dims(), hidden.dims());
+        // Every position adds the average of the first two values, 1.5, to itself
+        let rows = out.get(0).unwrap().to_vec2::<f32>().unwrap();
+        for (row, expected) in rows.iter().zip([2.5, 3.5, 4.5]) {
+            assert!(row.iter().all(|x| (x - expected).abs() < 1e-2));
+        }
+    }
+
+    #[test]
+    fn attention_ignores_padding() {
+        let device = Device::Cpu;
+        let hidden = BART_NUM_HEADS * BART_HEAD_DIM;
+        let q = Tensor::ones((2, 3, hidden), DType::F32, &device).unwrap();
+        let k = Tensor::ones((2, 3, hidden), DType::F32, &device).unwrap();
+        // The last position holds a value that would dominate the average if attended to
+        let v = Tensor::new(&[1f32, 1., 1000.], &device)
+            .unwrap()
+            .reshape((1, 3, 1))
+            .unwrap()
+            .broadcast_as((2, 3, hidden))
+            .unwrap()
+            .contiguous()
+            .unwrap();
+        // Only the first row of the batch is padded
+        let mask = Tensor::new(&[[1u8, 1, 0], [1, 1, 1]], &device).unwrap();
+
+        let attn = scaled_dot_product_attention(&q, &k, &v, Some(&mask), false).unwrap();
+        assert_eq!(attn.dims(), &[2, 3, hidden]);
+        let [padded, unpadded] = [0, 1].map(|i| attn.get(i).unwrap().to_vec2::<f32>().unwrap());
+        for row in padded {
+            assert!(row.iter().all(|x| (x - 1.0).abs() < 1e-4));
+        }
+        for row in unpadded {
+            assert!(row.iter().all(|x| (x - 334.0).abs() < 1e-1));
         }
+
+        // With a causal mask, the first two positions never see the third
+        let attn = scaled_dot_product_attention(&q, &k, &v, None, true).unwrap();
+        let rows = attn.get(0).unwrap().to_vec2::<f32>().unwrap();
+        assert!(rows[0].iter().all(|x| (x - 1.0).abs() < 1e-4));
+        assert!(rows[1].iter().all(|x| (x - 1.0).abs() < 1e-4));
+        assert!(rows[2].iter().all(|x| (x - 334.0).abs() < 1e-1));
+
+        // Masks and tensors that don't fit the heads are reported instead of broadcast
+        let short_mask = Tensor::new(&[[1u8, 1], [1, 1]], &device).unwrap();
+        assert!(matches!(
+            scaled_dot_product_attention(&q, &k, &v, Some(&short_mask), false),
+            Err(BartError::ShapeMismatch(_))
+        ));
+        let narrow = q.narrow(2, 0, BART_HEAD_DIM).unwrap();
+        assert!(matches!(
+            scaled_dot_product_attention(&narrow, &k, &v, None, false),
+            Err(BartError::ShapeMismatch(_))
+        ));
     }
 }
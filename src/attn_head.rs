@@ -1,8 +1,11 @@
 use candle_core::{quantized::QTensor, Device};
 
 use crate::{
-    bart_tensor_type::{AttnLayer, AttnType, TensorName, TensorType},
-    tensors::BartTensors,
+    bart_tensor_type::{
+        AttnBlock, AttnLayer, AttnType, OutProjLayer, Stack, TensorName, TensorType,
+    },
+    error::Result,
+    tensors::WeightSource,
 };
 
 pub struct NeuralNet {
@@ -13,6 +16,7 @@ pub struct AttnHead {
     q: NeuralNet,
     k: NeuralNet,
     v: NeuralNet,
+    out: NeuralNet,
 }
 
 impl AttnHead {
@@ -25,7 +29,16 @@ impl AttnHead {
     pub fn get_v(&self) -> &NeuralNet {
         &self.v
     }
-    pub fn new(layer: usize, tensors: &mut BartTensors, device: &Device) -> Result<Self, ()> {
+    pub fn get_out(&self) -> &NeuralNet {
+        &self.out
+    }
+    pub fn new(
+        stack: Stack,
+        block: AttnBlock,
+        layer: usize,
+        tensors: &mut dyn WeightSource,
+        device: &Device,
+    ) -> Result<Self> {
         let mut attn_tensors = Vec::with_capacity(6);
 
         let attns = [AttnType::Query, AttnType::Key, AttnType::Value];
@@ -33,18 +46,32 @@ impl AttnHead {
         for a in attns {
             for t in tensors_types {
                 let tensor = tensors.get_tensor(
-                    TensorName::SelfAttn(AttnLayer {
+                    TensorName::Attn(AttnLayer {
+                        stack,
+                        block,
                         attn_type: a,
                         tensor_type: t,
                         layer,
                     }),
                     device,
-                );
+                )?;
                 attn_tensors.push(tensor);
             }
         }
 
-        return Ok(AttnHead {
+        let [out_bias, out_weights] = tensors_types.map(|t| {
+            tensors.get_tensor(
+                TensorName::OutProj(OutProjLayer {
+                    stack,
+                    block,
+                    tensor_type: t,
+                    layer,
+                }),
+                device,
+            )
+        });
+
+        Ok(AttnHead {
             q: NeuralNet {
                 bias: attn_tensors.remove(0),
                 weights: attn_tensors.remove(0),
@@ -57,13 +84,18 @@ impl AttnHead {
                 bias: attn_tensors.remove(0),
                 weights: attn_tensors.remove(0),
             },
-        });
+            out: NeuralNet {
+                bias: out_bias?,
+                weights: out_weights?,
+            },
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::attn_head::AttnHead;
+    use crate::bart_tensor_type::{AttnBlock, Stack};
 
     #[test]
     fn loads_layers() {
@@ -73,7 +105,30 @@ mod tests {
 
         for i in 0..12 {
             println!("loading layer {i}");
-            AttnHead::new(i, &mut tensors, &device).unwrap();
+            AttnHead::new(
+                Stack::Encoder,
+                AttnBlock::SelfAttn,
+                i,
+                &mut tensors,
+                &device,
+            )
+            .unwrap();
+            AttnHead::new(
+                Stack::Decoder,
+                AttnBlock::SelfAttn,
+                i,
+                &mut tensors,
+                &device,
+            )
+            .unwrap();
+            AttnHead::new(
+                Stack::Decoder,
+                AttnBlock::EncoderAttn,
+                i,
+                &mut tensors,
+                &device,
+            )
+            .unwrap();
         }
     }
 }
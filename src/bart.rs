@@ -0,0 +1,246 @@
+use candle_core::{Device, Tensor};
+use tracing::debug;
+
+use crate::{
+    bart_tensor_type::{Stack, TensorName},
+    decoder::{BartDecoder, DecoderCache, DecoderHidden},
+    encoder::{BartEncoder, EncoderHidden},
+    error::{BartError, Result},
+    input::{BatchTokens, InputBatch},
+    layer_norm::LayerNorm,
+    tensors::WeightSource,
+    tokenizer::Tokenizer,
+};
+
+/// The encoder and decoder together with the language modeling head on top of the decoder
+pub struct BartForConditionalGeneration {
+    /// The token embeddings shared by the encoder, the decoder and the language modeling head
+    embed_tokens: Tensor,
+    encoder_embed_positions: Tensor,
+    decoder_embed_positions: Tensor,
+    encoder_layernorm_embedding: LayerNorm,
+    decoder_layernorm_embedding: LayerNorm,
+    encoder: BartEncoder,
+    decoder: BartDecoder,
+    final_logits_bias: Tensor,
+}
+
+impl BartForConditionalGeneration {
+    pub fn new(tensors: &mut dyn WeightSource, device: &Device) -> Result<Self> {
+        let mut dequantize = |tensor_name: TensorName| -> Result<Tensor> {
+            Ok(tensors
+                .get_tensor(tensor_name, device)?
+                .dequantize(device)?)
+        };
+        let embed_tokens = dequantize(TensorName::EmbedTokensWeights)?;
+        let encoder_embed_positions = dequantize(TensorName::EmbedPositionWeights(Stack::Encoder))?;
+        let decoder_embed_positions = dequantize(TensorName::EmbedPositionWeights(Stack::Decoder))?;
+        let final_logits_bias = dequantize(TensorName::FinalLogitsBias)?;
+
+        let (_, hidden_size) = embed_tokens.dims2()?;
+        for embed_positions in [&encoder_embed_positions, &decoder_embed_positions] {
+            let (_, positions_hidden_size) = embed_positions.dims2()?;
+            if positions_hidden_size != hidden_size {
+                return Err(BartError::ShapeMismatch(format!(
+                    "the position embeddings have {positions_hidden_size} dimensions but the token embeddings have {hidden_size}"
+                )));
+            }
+        }
+
+        let [encoder_layernorm_embedding, decoder_layernorm_embedding] =
+            [Stack::Encoder, Stack::Decoder].map(|stack| {
+                LayerNorm::new(
+                    |t| TensorName::LayerNormEmbedding(stack, t),
+                    tensors,
+                    device,
+                )
+            });
+
+        Ok(BartForConditionalGeneration {
+            embed_tokens,
+            encoder_embed_positions,
+            decoder_embed_positions,
+            encoder_layernorm_embedding: encoder_layernorm_embedding?,
+            decoder_layernorm_embedding: decoder_layernorm_embedding?,
+            encoder: BartEncoder::new(tensors, device)?,
+            decoder: BartDecoder::new(tensors, device)?,
+            final_logits_bias,
+        })
+    }
+
+    /// The number of tokens the model has embeddings for
+    pub fn vocab_size(&self) -> Result<usize> {
+        Ok(self.embed_tokens.dim(0)?)
+    }
+
+    /// Resizes the token embeddings to `vocab_size` rows, like HF's `resize_token_embeddings`,
+    /// so that the tokens added to the tokenizer can be embedded and generated.
+    /// New rows are drawn from N(0, 0.02) like BART's initialization and have no logit bias.
+    /// Shrinking drops the last rows.
+    pub fn resize_token_embeddings(&mut self, vocab_size: usize) -> Result<()> {
+        let (old_size, hidden_size) = self.embed_tokens.dims2()?;
+        // The bias is stored as either `(vocab_size,)` or `(1, vocab_size)`
+        let bias_dim = self.final_logits_bias.rank() - 1;
+        if vocab_size <= old_size {
+            self.embed_tokens = self.embed_tokens.narrow(0, 0, vocab_size)?;
+            self.final_logits_bias = self.final_logits_bias.narrow(bias_dim, 0, vocab_size)?;
+        } else {
+            let device = self.embed_tokens.device();
+            let new_rows = Tensor::randn(0f32, 0.02, (vocab_size - old_size, hidden_size), device)?
+                .to_dtype(self.embed_tokens.dtype())?;
+            let mut bias_dims = self.final_logits_bias.dims().to_vec();
+            bias_dims[bias_dim] = vocab_size - old_size;
+            let new_bias = Tensor::zeros(bias_dims, self.final_logits_bias.dtype(), device)?;
+            self.embed_tokens = Tensor::cat(&[&self.embed_tokens, &new_rows], 0)?;
+            self.final_logits_bias = Tensor::cat(&[&self.final_logits_bias, &new_bias], bias_dim)?;
+        }
+        debug!("Resized token embeddings from {old_size} to {vocab_size} tokens");
+        Ok(())
+    }
+
+    /// Grows the token embeddings to cover every token of `tokenizer`, which is needed
+    /// once tokens are added to the tokenizer. A model with more embeddings is left as is.
+    pub fn fit_tokenizer(&mut self, tokenizer: &dyn Tokenizer) -> Result<()> {
+        let vocab_size = tokenizer.vocab_size();
+        if vocab_size > self.vocab_size()? {
+            self.resize_token_embeddings(vocab_size)?;
+        }
+        Ok(())
+    }
+
+    /// Embeds the formatted batch and runs it through the encoder
+    pub fn encode(
+        &self,
+        input: InputBatch<BatchTokens>,
+        device: &Device,
+    ) -> Result<InputBatch<EncoderHidden>> {
+        let input = input.embed(&self.embed_tokens)?.add_pos_embeds(
+            &self.encoder_embed_positions,
+            0,
+            &self.encoder_layernorm_embedding,
+        )?;
+        self.encoder.encode(input, device)
+    }
+
+    /// Embeds the newly generated tokens and runs them through the decoder.
+    /// The tokens generated before them are read from `cache`.
+    pub fn decode(
+        &self,
+        decoder_input: InputBatch<BatchTokens>,
+        encoder_hidden: &InputBatch<EncoderHidden>,
+        cache: &mut DecoderCache,
+        device: &Device,
+    ) -> Result<InputBatch<DecoderHidden>> {
+        // The new tokens continue from the positions already in the cache
+        let decoder_input = decoder_input.embed(&self.embed_tokens)?.add_pos_embeds(
+            &self.decoder_embed_positions,
+            cache.past_len()?,
+            &self.decoder_layernorm_embedding,
+        )?;
+        self.decoder
+            .decode(decoder_input, encoder_hidden, cache, device)
+    }
+
+    /// Projects the decoder's output onto the vocabulary. The projection is tied
+    /// to the token embeddings, so no separate weights are loaded for it.
+    /// Returns a `(batch, decoder_len, vocab_size)` tensor of logits.
+    pub fn lm_head(&self, decoder_hidden: &InputBatch<DecoderHidden>) -> Result<Tensor> {
+        let hidden = decoder_hidden
+            .get_last_hidden_state()
+            .to_dtype(self.embed_tokens.dtype())?;
+        Ok(hidden
+            .broadcast_matmul(&self.embed_tokens.t()?)?
+            .broadcast_add(&self.final_logits_bias)?)
+    }
+
+    /// Runs the decoder followed by the language modeling head
+    pub fn forward(
+        &self,
+        decoder_input: InputBatch<BatchTokens>,
+        encoder_hidden: &InputBatch<EncoderHidden>,
+        cache: &mut DecoderCache,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let decoder_hidden = self.decode(decoder_input, encoder_hidden, cache, device)?;
+        self.lm_head(&decoder_hidden)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{InputSeq, Truncation};
+    use crate::tokenizer::{AddedToken, Token, WordPieceTokenizer};
+
+    /// The number of tokens in bart-large-cnn's vocabulary
+    const BART_VOCAB_SIZE: usize = 50265;
+
+    #[test]
+    fn returns_vocab_logits() {
+        let model_path = "bart-large-cnn/bart-large-cnn_f16.gguf";
+        let mut tensors = crate::tensors::BartTensors::new(&model_path).unwrap();
+        let device = candle_core::Device::new_metal(0).unwrap();
+        let mut tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
+        let mut model = BartForConditionalGeneration::new(&mut tensors, &device).unwrap();
+
+        let input_seq = InputSeq::new("The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration".into());
+        let input_seq = input_seq
+            .tokenize(&tokenizer)
+            .format_for_bart(Truncation::LongestFirst)
+            .unwrap();
+        let encoded = model.encode(input_seq.into_batch(), &device).unwrap();
+
+        let decoder_input =
+            ["</s>", "<s>", "ĠThe"].map(|s| Token::from_substr(&tokenizer, s).unwrap());
+        let logits = model
+            .forward(
+                InputSeq::from_tokens(decoder_input.into()).into_batch(),
+                &encoded,
+                &mut DecoderCache::default(),
+                &device,
+            )
+            .unwrap();
+        assert_eq!(logits.dims(), &[1, 3, BART_VOCAB_SIZE]);
+        let logits = logits.get(0).unwrap();
+
+        // Feeding the tokens one at a time through the cache gives the same logits
+        let mut cache = DecoderCache::default();
+        for (i, token) in decoder_input.into_iter().enumerate() {
+            let step_logits = model
+                .forward(
+                    InputSeq::from_tokens([token].into()).into_batch(),
+                    &encoded,
+                    &mut cache,
+                    &device,
+                )
+                .unwrap();
+            let step_logits = step_logits.get(0).unwrap().get(0).unwrap();
+            let diff = (step_logits - logits.get(i).unwrap())
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert!(diff < 1e-1, "step {i} differs by {diff}");
+        }
+
+        // Added tokens get their own rows, which the logits cover as well
+        model.fit_tokenizer(&tokenizer).unwrap();
+        assert_eq!(model.vocab_size().unwrap(), BART_VOCAB_SIZE);
+        let added = ["<a>", "<b>", "<c>"].map(AddedToken::new);
+        assert_eq!(tokenizer.add_tokens(&added), 3);
+        model.fit_tokenizer(&tokenizer).unwrap();
+        assert_eq!(model.vocab_size().unwrap(), BART_VOCAB_SIZE + 3);
+        let logits = model
+            .forward(
+                InputSeq::from_tokens(decoder_input.into()).into_batch(),
+                &encoded,
+                &mut DecoderCache::default(),
+                &device,
+            )
+            .unwrap();
+        assert_eq!(logits.dims(), &[1, 3, BART_VOCAB_SIZE + 3]);
+    }
+}
@@ -9,6 +9,26 @@ pub enum TensorType {
     Weight,
 }
 
+/// Whether a tensor belongs to the encoder or the decoder
+#[derive(Clone, Copy, Debug, Display)]
+#[repr(u8)]
+pub enum Stack {
+    #[display(fmt = "encoder")]
+    Encoder,
+    #[display(fmt = "decoder")]
+    Decoder,
+}
+
+/// The attention blocks of a layer. Only decoder layers have an `encoder_attn` block.
+#[derive(Clone, Copy, Debug, Display)]
+#[repr(u8)]
+pub enum AttnBlock {
+    #[display(fmt = "self_attn")]
+    SelfAttn,
+    #[display(fmt = "encoder_attn")]
+    EncoderAttn,
+}
+
 #[derive(Clone, Copy, Debug, Display)]
 #[repr(u8)]
 pub enum AttnType {
@@ -22,12 +42,16 @@ pub enum AttnType {
 
 #[derive(Clone, Debug, Display)]
 #[display(
-    fmt = "model.encoder.layers.{}.self_attn.{}.{}",
+    fmt = "model.{}.layers.{}.{}.{}.{}",
+    "stack",
     "layer",
+    "block",
     "attn_type",
     "tensor_type"
 )]
 pub struct AttnLayer {
+    pub stack: Stack,
+    pub block: AttnBlock,
     pub attn_type: AttnType,
     pub tensor_type: TensorType,
     pub layer: usize,
@@ -35,23 +59,86 @@ pub struct AttnLayer {
 
 #[derive(Clone, Debug, Display)]
 #[display(
-    fmt = "model.encoder.layers.{}.self_attn.out_proj.{}",
+    fmt = "model.{}.layers.{}.{}.out_proj.{}",
+    "stack",
     "layer",
+    "block",
     "tensor_type"
 )]
 pub struct OutProjLayer {
+    pub stack: Stack,
+    pub block: AttnBlock,
+    pub tensor_type: TensorType,
+    pub layer: usize,
+}
+
+#[derive(Clone, Copy, Debug, Display)]
+#[repr(u8)]
+pub enum FcType {
+    #[display(fmt = "fc1")]
+    Fc1,
+    #[display(fmt = "fc2")]
+    Fc2,
+}
+
+#[derive(Clone, Debug, Display)]
+#[display(
+    fmt = "model.{}.layers.{}.{}.{}",
+    "stack",
+    "layer",
+    "fc_type",
+    "tensor_type"
+)]
+pub struct FcLayer {
+    pub stack: Stack,
+    pub fc_type: FcType,
+    pub tensor_type: TensorType,
+    pub layer: usize,
+}
+
+#[derive(Clone, Copy, Debug, Display)]
+#[repr(u8)]
+pub enum LayerNormType {
+    #[display(fmt = "self_attn_layer_norm")]
+    SelfAttn,
+    #[display(fmt = "encoder_attn_layer_norm")]
+    EncoderAttn,
+    #[display(fmt = "final_layer_norm")]
+    Final,
+}
+
+#[derive(Clone, Debug, Display)]
+#[display(
+    fmt = "model.{}.layers.{}.{}.{}",
+    "stack",
+    "layer",
+    "norm_type",
+    "tensor_type"
+)]
+pub struct LayerNormLayer {
+    pub stack: Stack,
+    pub norm_type: LayerNormType,
     pub tensor_type: TensorType,
     pub layer: usize,
 }
 
 #[derive(Clone, Debug, Display)]
 pub enum TensorName {
-    #[display(fmt = "model.decoder.embed_positions.weight")]
-    EmbedPositionWeights,
+    /// The encoder and the decoder each learn their own positional embeddings
+    #[display(fmt = "model.{}.embed_positions.weight", _0)]
+    EmbedPositionWeights(Stack),
     #[display(fmt = "model.decoder.embed_tokens.weight")]
     EmbedTokensWeights,
+    #[display(fmt = "final_logits_bias")]
+    FinalLogitsBias,
     #[display(fmt = "{}", _0)]
-    SelfAttn(AttnLayer),
+    Attn(AttnLayer),
     #[display(fmt = "{}", _0)]
     OutProj(OutProjLayer),
+    #[display(fmt = "{}", _0)]
+    Fc(FcLayer),
+    #[display(fmt = "{}", _0)]
+    LayerNorm(LayerNormLayer),
+    #[display(fmt = "model.{}.layernorm_embedding.{}", _0, _1)]
+    LayerNormEmbedding(Stack, TensorType),
 }
@@ -0,0 +1,250 @@
+use candle_core::{Device, Tensor};
+use tracing::debug;
+
+use crate::{
+    attn::KvCache,
+    attn_head::AttnHead,
+    bart_tensor_type::{AttnBlock, LayerNormLayer, LayerNormType, Stack, TensorName},
+    encoder::EncoderHidden,
+    error::Result,
+    feed_forward::FeedForward,
+    input::{InputBatch, InputData, PositionedEmbeddings},
+    layer_norm::LayerNorm,
+    tensors::WeightSource,
+};
+
+/// BART Large has 12 decoder layers
+pub const BART_DECODER_LAYERS: usize = 12;
+
+/// The output of the last decoder layer
+#[derive(Clone)]
+pub struct DecoderHidden(Tensor);
+impl InputData for DecoderHidden {}
+
+impl InputBatch<DecoderHidden> {
+    pub fn get_last_hidden_state(&self) -> &Tensor {
+        &self.state.0
+    }
+}
+
+/// The attention caches of a single decoder layer
+#[derive(Clone, Default)]
+pub struct LayerCache {
+    self_attn: KvCache,
+    encoder_attn: KvCache,
+}
+
+/// The attention caches of every decoder layer, carried from one decoding step to the next.
+/// A new cache must be created for every new encoder output.
+#[derive(Clone)]
+pub struct DecoderCache {
+    layers: Vec<LayerCache>,
+}
+
+impl Default for DecoderCache {
+    fn default() -> Self {
+        Self {
+            layers: vec![LayerCache::default(); BART_DECODER_LAYERS],
+        }
+    }
+}
+
+impl DecoderCache {
+    /// The number of decoder positions that were already processed
+    pub fn past_len(&self) -> Result<usize> {
+        self.layers[0].self_attn.len()
+    }
+
+    /// Rearranges the batch rows so that row `i` continues from row `indices[i]`.
+    /// Only the self-attention caches are reordered: rows must only be taken from
+    /// rows that share the same encoder output, whose cross-attention keys and values are identical.
+    pub fn reorder(&mut self, indices: &Tensor) -> Result<()> {
+        for layer in &mut self.layers {
+            layer.self_attn.reorder(indices)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single BART decoder layer: causal self-attention, cross-attention over the
+/// encoder's output and the feed-forward network, each followed by a layer norm
+pub struct DecoderLayer {
+    self_attn: AttnHead,
+    self_attn_layer_norm: LayerNorm,
+    encoder_attn: AttnHead,
+    encoder_attn_layer_norm: LayerNorm,
+    ffn: FeedForward,
+    final_layer_norm: LayerNorm,
+}
+
+impl DecoderLayer {
+    pub fn new(layer: usize, tensors: &mut dyn WeightSource, device: &Device) -> Result<Self> {
+        let layer_norm = |norm_type| {
+            move |tensor_type| {
+                TensorName::LayerNorm(LayerNormLayer {
+                    stack: Stack::Decoder,
+                    norm_type,
+                    tensor_type,
+                    layer,
+                })
+            }
+        };
+        Ok(DecoderLayer {
+            self_attn: AttnHead::new(Stack::Decoder, AttnBlock::SelfAttn, layer, tensors, device)?,
+            self_attn_layer_norm: LayerNorm::new(
+                layer_norm(LayerNormType::SelfAttn),
+                tensors,
+                device,
+            )?,
+            encoder_attn: AttnHead::new(
+                Stack::Decoder,
+                AttnBlock::EncoderAttn,
+                layer,
+                tensors,
+                device,
+            )?,
+            encoder_attn_layer_norm: LayerNorm::new(
+                layer_norm(LayerNormType::EncoderAttn),
+                tensors,
+                device,
+            )?,
+            ffn: FeedForward::new(Stack::Decoder, layer, tensors, device)?,
+            final_layer_norm: LayerNorm::new(layer_norm(LayerNormType::Final), tensors, device)?,
+        })
+    }
+
+    pub fn forward(
+        &self,
+        hidden: &Tensor,
+        encoder_hidden: &Tensor,
+        encoder_attention_mask: Option<&Tensor>,
+        cache: &mut LayerCache,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let hidden = self
+            .self_attn
+            .forward_causal(hidden, &mut cache.self_attn, device)?;
+        let hidden = self.self_attn_layer_norm.forward(&hidden)?;
+        let hidden = self.encoder_attn.forward_cross(
+            &hidden,
+            encoder_hidden,
+            encoder_attention_mask,
+            &mut cache.encoder_attn,
+            device,
+        )?;
+        let hidden = self.encoder_attn_layer_norm.forward(&hidden)?;
+        let hidden = self.ffn.forward(&hidden, device)?;
+        self.final_layer_norm.forward(&hidden)
+    }
+}
+
+/// The full stack of decoder layers
+pub struct BartDecoder {
+    layers: Vec<DecoderLayer>,
+}
+
+impl BartDecoder {
+    pub fn new(tensors: &mut dyn WeightSource, device: &Device) -> Result<Self> {
+        let layers = (0..BART_DECODER_LAYERS)
+            .map(|i| {
+                debug!("Loading decoder layer {i}");
+                DecoderLayer::new(i, tensors, device)
+            })
+            .collect::<Result<_>>()?;
+        Ok(BartDecoder { layers })
+    }
+
+    /// Runs the embedded decoder input through every decoder layer, attending to
+    /// the encoder's output while ignoring the encoder's padding.
+    /// `input` holds the positions that come after the ones already stored in `cache`.
+    pub fn decode(
+        &self,
+        input: InputBatch<PositionedEmbeddings>,
+        encoder_hidden: &InputBatch<EncoderHidden>,
+        cache: &mut DecoderCache,
+        device: &Device,
+    ) -> Result<InputBatch<DecoderHidden>> {
+        let encoder_attention_mask = Some(encoder_hidden.get_attention_mask());
+        let encoder_hidden = encoder_hidden.get_last_hidden_state();
+        let mut hidden = input.get_embeds().clone();
+        for (i, (layer, layer_cache)) in self.layers.iter().zip(&mut cache.layers).enumerate() {
+            debug!("Running decoder layer {i}");
+            hidden = layer.forward(
+                &hidden,
+                encoder_hidden,
+                encoder_attention_mask,
+                layer_cache,
+                device,
+            )?;
+        }
+        Ok(InputBatch {
+            state: DecoderHidden(hidden),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::BartEncoder;
+    use crate::input::{InputSeq, Truncation};
+    use crate::tokenizer::{Token, WordPieceTokenizer};
+
+    #[test]
+    fn decodes_sequence() {
+        let model_path = "bart-large-cnn/bart-large-cnn_f16.gguf";
+        let mut tensors = crate::tensors::BartTensors::new(&model_path).unwrap();
+        let device = candle_core::Device::new_metal(0).unwrap();
+        let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
+
+        let token_embeds = tensors
+            .get_tensor(TensorName::EmbedTokensWeights, &device)
+            .unwrap()
+            .dequantize(&device)
+            .unwrap();
+        let [encoder_pos_embeds, decoder_pos_embeds] =
+            [Stack::Encoder, Stack::Decoder].map(|stack| {
+                tensors
+                    .get_tensor(TensorName::EmbedPositionWeights(stack), &device)
+                    .unwrap()
+                    .dequantize(&device)
+                    .unwrap()
+            });
+        let [encoder_norm, decoder_norm] = [Stack::Encoder, Stack::Decoder].map(|stack| {
+            LayerNorm::new(
+                |t| TensorName::LayerNormEmbedding(stack, t),
+                &mut tensors,
+                &device,
+            )
+            .unwrap()
+        });
+        let encoder = BartEncoder::new(&mut tensors, &device).unwrap();
+        let decoder = BartDecoder::new(&mut tensors, &device).unwrap();
+
+        let input_seq = InputSeq::new("The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration".into());
+        let input_seq = input_seq
+            .tokenize(&tokenizer)
+            .format_for_bart(Truncation::LongestFirst)
+            .unwrap()
+            .into_batch()
+            .embed(&token_embeds)
+            .unwrap()
+            .add_pos_embeds(&encoder_pos_embeds, 0, &encoder_norm)
+            .unwrap();
+        let encoded = encoder.encode(input_seq, &device).unwrap();
+
+        let decoder_input = ["</s>", "<s>"].map(|s| Token::from_substr(&tokenizer, s).unwrap());
+        let decoder_input = InputSeq::from_tokens(decoder_input.into())
+            .into_batch()
+            .embed(&token_embeds)
+            .unwrap()
+            .add_pos_embeds(&decoder_pos_embeds, 0, &decoder_norm)
+            .unwrap();
+        let mut cache = DecoderCache::default();
+        let decoded = decoder
+            .decode(decoder_input, &encoded, &mut cache, &device)
+            .unwrap();
+        assert_eq!(cache.past_len().unwrap(), 2);
+        assert_eq!(decoded.get_last_hidden_state().dims(), &[1, 2, 1024]);
+    }
+}
@@ -0,0 +1,187 @@
+use candle_core::{Device, Tensor};
+use tracing::debug;
+
+use crate::{
+    attn_head::AttnHead,
+    bart_tensor_type::{AttnBlock, LayerNormLayer, LayerNormType, Stack, TensorName},
+    error::Result,
+    feed_forward::FeedForward,
+    input::{InputBatch, InputData, PositionedEmbeddings},
+    layer_norm::LayerNorm,
+    tensors::WeightSource,
+};
+
+/// BART Large has 12 encoder layers
+pub const BART_ENCODER_LAYERS: usize = 12;
+
+/// The output of the last encoder layer
+#[derive(Clone)]
+pub struct EncoderHidden {
+    last_hidden_state: Tensor,
+    attention_mask: Tensor,
+}
+impl InputData for EncoderHidden {}
+
+impl InputBatch<EncoderHidden> {
+    pub fn get_last_hidden_state(&self) -> &Tensor {
+        &self.state.last_hidden_state
+    }
+
+    /// The attention mask of the encoder's input, so the decoder can ignore its padding
+    pub fn get_attention_mask(&self) -> &Tensor {
+        &self.state.attention_mask
+    }
+
+    /// Repeats every row of the batch `n` times in a row, so each of the `n` beams
+    /// of a sequence can attend to its encoder output
+    pub fn repeat_rows(&self, n: usize) -> Result<Self> {
+        let batch_size = self.state.last_hidden_state.dim(0)?;
+        let indices: Vec<u32> = (0..batch_size * n).map(|i| (i / n) as u32).collect();
+        let indices = Tensor::new(indices, self.state.last_hidden_state.device())?;
+        Ok(InputBatch {
+            state: EncoderHidden {
+                last_hidden_state: self.state.last_hidden_state.index_select(&indices, 0)?,
+                attention_mask: self.state.attention_mask.index_select(&indices, 0)?,
+            },
+        })
+    }
+}
+
+/// A single BART encoder layer. BART normalizes after each residual connection (post-LN).
+pub struct EncoderLayer {
+    self_attn: AttnHead,
+    self_attn_layer_norm: LayerNorm,
+    ffn: FeedForward,
+    final_layer_norm: LayerNorm,
+}
+
+impl EncoderLayer {
+    pub fn new(layer: usize, tensors: &mut dyn WeightSource, device: &Device) -> Result<Self> {
+        let layer_norm = |norm_type| {
+            move |tensor_type| {
+                TensorName::LayerNorm(LayerNormLayer {
+                    stack: Stack::Encoder,
+                    norm_type,
+                    tensor_type,
+                    layer,
+                })
+            }
+        };
+        Ok(EncoderLayer {
+            self_attn: AttnHead::new(Stack::Encoder, AttnBlock::SelfAttn, layer, tensors, device)?,
+            self_attn_layer_norm: LayerNorm::new(
+                layer_norm(LayerNormType::SelfAttn),
+                tensors,
+                device,
+            )?,
+            ffn: FeedForward::new(Stack::Encoder, layer, tensors, device)?,
+            final_layer_norm: LayerNorm::new(layer_norm(LayerNormType::Final), tensors, device)?,
+        })
+    }
+
+    pub fn forward(
+        &self,
+        hidden: &Tensor,
+        attention_mask: Option<&Tensor>,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let hidden = self.self_attn.forward(hidden, attention_mask, device)?;
+        let hidden = self.self_attn_layer_norm.forward(&hidden)?;
+        let hidden = self.ffn.forward(&hidden, device)?;
+        self.final_layer_norm.forward(&hidden)
+    }
+}
+
+/// The full stack of encoder layers
+pub struct BartEncoder {
+    layers: Vec<EncoderLayer>,
+}
+
+impl BartEncoder {
+    pub fn new(tensors: &mut dyn WeightSource, device: &Device) -> Result<Self> {
+        let layers = (0..BART_ENCODER_LAYERS)
+            .map(|i| {
+                debug!("Loading encoder layer {i}");
+                EncoderLayer::new(i, tensors, device)
+            })
+            .collect::<Result<_>>()?;
+        Ok(BartEncoder { layers })
+    }
+
+    /// Runs the embedded input through every encoder layer, ignoring its padding
+    pub fn encode(
+        &self,
+        input: InputBatch<PositionedEmbeddings>,
+        device: &Device,
+    ) -> Result<InputBatch<EncoderHidden>> {
+        let attention_mask = input.get_attention_mask();
+        let mut hidden = input.get_embeds().clone();
+        for (i, layer) in self.layers.iter().enumerate() {
+            debug!("Running encoder layer {i}");
+            hidden = layer.forward(&hidden, Some(attention_mask), device)?;
+        }
+        Ok(InputBatch {
+            state: EncoderHidden {
+                last_hidden_state: hidden,
+                attention_mask: attention_mask.clone(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Truncation;
+    use crate::layer_norm::LayerNorm;
+    use crate::tokenizer::WordPieceTokenizer;
+
+    #[test]
+    fn encodes_sequence() {
+        let model_path = "bart-large-cnn/bart-large-cnn_f16.gguf";
+        let mut tensors = crate::tensors::BartTensors::new(&model_path).unwrap();
+        let device = candle_core::Device::new_metal(0).unwrap();
+        let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
+
+        let token_embeds = tensors
+            .get_tensor(TensorName::EmbedTokensWeights, &device)
+            .unwrap();
+        let pos_embeds = tensors
+            .get_tensor(TensorName::EmbedPositionWeights(Stack::Encoder), &device)
+            .unwrap();
+        let layernorm_embedding = LayerNorm::new(
+            |t| TensorName::LayerNormEmbedding(Stack::Encoder, t),
+            &mut tensors,
+            &device,
+        )
+        .unwrap();
+        let encoder = BartEncoder::new(&mut tensors, &device).unwrap();
+
+        // The shorter text is padded to the length of the longer one
+        let texts = [
+            "The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration",
+            "We propose a new simple network architecture",
+        ];
+        let input_batch =
+            InputBatch::new(texts.map(Into::into), &tokenizer, Truncation::LongestFirst).unwrap();
+        let seq_len = input_batch.get_seqs()[0].get_tokens().len();
+        let input_batch = input_batch
+            .embed(&token_embeds.dequantize(&device).unwrap())
+            .unwrap()
+            .add_pos_embeds(
+                &pos_embeds.dequantize(&device).unwrap(),
+                0,
+                &layernorm_embedding,
+            )
+            .unwrap();
+        let shape = input_batch.get_embeds().shape().clone();
+
+        let hidden = encoder.encode(input_batch, &device).unwrap();
+        assert_eq!(hidden.get_last_hidden_state().shape(), &shape);
+        assert_eq!(shape.dims(), &[2, seq_len, 1024]);
+        assert_eq!(
+            hidden.repeat_rows(4).unwrap().get_attention_mask().dims(),
+            &[8, seq_len]
+        );
+    }
+}
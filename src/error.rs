@@ -0,0 +1,68 @@
+use thiserror::Error;
+
+use crate::bart_tensor_type::TensorName;
+
+/// Everything that can go wrong while loading or running BART, so that a bad model file
+/// or input is reported to the caller instead of crashing the process
+#[derive(Debug, Error)]
+pub enum BartError {
+    #[error("tensor {0} not found in the model file")]
+    MissingTensor(TensorName),
+    #[error("shape mismatch: {0}")]
+    ShapeMismatch(String),
+    #[error("tokenizer error: {0}")]
+    Tokenizer(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid config: {0}")]
+    Config(String),
+    #[error("device error: {0}")]
+    Device(String),
+    #[error("generation error: {0}")]
+    Generation(String),
+    /// Any other error of a tensor operation
+    #[error(transparent)]
+    Tensor(candle_core::Error),
+}
+
+pub type Result<T> = std::result::Result<T, BartError>;
+
+impl From<candle_core::Error> for BartError {
+    fn from(error: candle_core::Error) -> Self {
+        use candle_core::Error;
+        match error {
+            Error::UnexpectedNumberOfDims { .. }
+            | Error::UnexpectedShape { .. }
+            | Error::ShapeMismatch { .. }
+            | Error::ShapeMismatchBinaryOp { .. }
+            | Error::ShapeMismatchCat { .. }
+            | Error::ShapeMismatchSplit { .. }
+            | Error::BroadcastIncompatibleShapes { .. }
+            | Error::DimOutOfRange { .. }
+            | Error::NarrowInvalidArgs { .. } => Self::ShapeMismatch(error.to_string()),
+            Error::DeviceMismatchBinaryOp { .. }
+            | Error::NotCompiledWithCudaSupport
+            | Error::NotCompiledWithMetalSupport
+            | Error::Cuda(_)
+            | Error::Metal(_) => Self::Device(error.to_string()),
+            Error::Io(error) => Self::Io(error),
+            Error::WithBacktrace { inner, .. } => Self::from(*inner),
+            error => Self::Tensor(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device, Tensor};
+
+    #[test]
+    fn maps_tensor_errors() {
+        let device = Device::Cpu;
+        let a = Tensor::zeros((2, 3), DType::F32, &device).unwrap();
+        let b = Tensor::zeros((4, 5), DType::F32, &device).unwrap();
+        let error = BartError::from(a.matmul(&b).unwrap_err());
+        assert!(matches!(error, BartError::ShapeMismatch(_)), "{error:?}");
+    }
+}
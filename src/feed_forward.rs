@@ -0,0 +1,86 @@
+use candle_core::{Device, Tensor};
+
+use crate::{
+    attn_head::NeuralNet,
+    bart_tensor_type::{FcLayer, FcType, Stack, TensorName, TensorType},
+    error::{BartError, Result},
+    tensors::WeightSource,
+};
+
+/// The position-wise feed-forward network at the end of every layer,
+/// expanding the 1024 hidden dimensions to 4096 and back again
+pub struct FeedForward {
+    fc1: NeuralNet,
+    fc2: NeuralNet,
+}
+
+impl FeedForward {
+    pub fn get_fc1(&self) -> &NeuralNet {
+        &self.fc1
+    }
+    pub fn get_fc2(&self) -> &NeuralNet {
+        &self.fc2
+    }
+    pub fn new(
+        stack: Stack,
+        layer: usize,
+        tensors: &mut dyn WeightSource,
+        device: &Device,
+    ) -> Result<Self> {
+        let [fc1, fc2] = [FcType::Fc1, FcType::Fc2].map(|fc_type| {
+            let [bias, weights] = [TensorType::Bias, TensorType::Weight].map(|tensor_type| {
+                tensors.get_tensor(
+                    TensorName::Fc(FcLayer {
+                        stack,
+                        fc_type,
+                        tensor_type,
+                        layer,
+                    }),
+                    device,
+                )
+            });
+            Ok::<_, BartError>(NeuralNet {
+                bias: bias?,
+                weights: weights?,
+            })
+        });
+
+        Ok(FeedForward {
+            fc1: fc1?,
+            fc2: fc2?,
+        })
+    }
+
+    /// Runs `fc2(gelu(fc1(hidden)))` and adds the result back onto `hidden`.
+    /// The result has the same shape and dtype as `hidden`.
+    pub fn forward(&self, hidden: &Tensor, device: &Device) -> Result<Tensor> {
+        // BART uses the exact erf formulation of GeLU rather than the tanh approximation
+        let expanded = self.get_fc1().forward(hidden, device)?.gelu_erf()?;
+        let out = self.get_fc2().forward(&expanded, device)?;
+        Ok((hidden + out.to_dtype(hidden.dtype())?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeedForward;
+    use crate::bart_tensor_type::Stack;
+    use candle_core::{DType, Tensor};
+
+    #[test]
+    fn keeps_hidden_shape() {
+        let model_path = "bart-large-cnn/bart-large-cnn_f16.gguf";
+        let mut tensors = crate::tensors::BartTensors::new(&model_path).unwrap();
+        let device = candle_core::Device::new_metal(0).unwrap();
+
+        let hidden = Tensor::zeros((8, 1024), DType::F32, &device).unwrap();
+        for stack in [Stack::Encoder, Stack::Decoder] {
+            for i in 0..12 {
+                println!("loading {stack} layer {i}");
+                let ffn = FeedForward::new(stack, i, &mut tensors, &device).unwrap();
+                let out = ffn.forward(&hidden, &device).unwrap();
+                assert_eq!(out.shape(), hidden.shape());
+            }
+        }
+    }
+}
@@ -0,0 +1,570 @@
+use std::{fs, path::Path};
+
+use candle_core::{Device, Tensor};
+use tracing::debug;
+
+use crate::{
+    bart::BartForConditionalGeneration,
+    decoder::DecoderCache,
+    encoder::EncoderHidden,
+    error::{BartError, Result},
+    input::{BatchTokens, InputBatch, InputSeq, Overflow, RawText},
+    tokenizer::{Token, Tokenizer},
+};
+
+/// The parameters that control how a summary is generated, as found in a checkpoint's
+/// `generation_config.json`. Values missing from the file default to bart-large-cnn's.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct GenerationConfig {
+    pub num_beams: usize,
+    /// Scores are divided by `length ^ length_penalty`, so values above 0 favor longer summaries
+    pub length_penalty: f64,
+    /// No n-gram of this size may appear twice in a summary. 0 disables the restriction.
+    pub no_repeat_ngram_size: usize,
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Stop as soon as `num_beams` sequences are finished instead of when no better one can be found
+    pub early_stopping: bool,
+    pub decoder_start_token_id: u32,
+    pub forced_bos_token_id: Option<u32>,
+    pub forced_eos_token_id: Option<u32>,
+    pub eos_token_id: u32,
+    /// Appended to the rows of a batch that finish before the others
+    pub pad_token_id: u32,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            num_beams: 4,
+            length_penalty: 2.0,
+            no_repeat_ngram_size: 3,
+            min_length: 56,
+            max_length: 142,
+            early_stopping: true,
+            decoder_start_token_id: 2,
+            forced_bos_token_id: Some(0),
+            forced_eos_token_id: Some(2),
+            eos_token_id: 2,
+            pad_token_id: 1,
+        }
+    }
+}
+
+impl GenerationConfig {
+    pub fn new<T: AsRef<Path>>(config_path: T) -> Result<Self> {
+        Self::from_json_str(&fs::read_to_string(config_path)?)
+    }
+
+    /// Like [`GenerationConfig::new`], but from the contents of the file.
+    /// Fails if the config can't be generated from, such as with no beams.
+    pub fn from_json_str(contents: &str) -> Result<Self> {
+        let config: Self =
+            serde_json::from_str(contents).map_err(|e| BartError::Config(e.to_string()))?;
+        config.beam_count()?;
+        debug!("Loaded generation config {config:?}");
+        Ok(config)
+    }
+
+    /// The number of beams to keep, which beam search needs at least one of
+    fn beam_count(&self) -> Result<usize> {
+        match self.num_beams {
+            0 => Err(BartError::Config("num_beams must be at least 1".into())),
+            num_beams => Ok(num_beams),
+        }
+    }
+}
+
+fn token_from_id(tokenizer: &dyn Tokenizer, id: u32) -> Result<Token> {
+    Token::new(tokenizer, id)
+        .ok_or_else(|| BartError::Tokenizer(format!("token {id} is not in the vocab")))
+}
+
+/// Converts logits into log-probabilities
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|x| x - log_sum).collect()
+}
+
+/// Applies the restrictions of `config` to the log-probabilities of the token following `tokens`
+fn process_logprobs(tokens: &[Token], logprobs: &mut [f32], config: &GenerationConfig) {
+    let cur_len = tokens.len();
+    let force = |logprobs: &mut [f32], id: u32| {
+        for (i, logprob) in logprobs.iter_mut().enumerate() {
+            if i != id as usize {
+                *logprob = f32::NEG_INFINITY;
+            }
+        }
+    };
+
+    let n = config.no_repeat_ngram_size;
+    if n > 0 && cur_len + 1 >= n {
+        let prefix = &tokens[cur_len + 1 - n..];
+        for ngram in tokens.windows(n) {
+            if &ngram[..n - 1] == prefix {
+                logprobs[ngram[n - 1].get_id() as usize] = f32::NEG_INFINITY;
+            }
+        }
+    }
+    if cur_len < config.min_length {
+        logprobs[config.eos_token_id as usize] = f32::NEG_INFINITY;
+    }
+    if let (Some(bos), 1) = (config.forced_bos_token_id, cur_len) {
+        force(logprobs, bos);
+    }
+    if let Some(eos) = config.forced_eos_token_id {
+        if cur_len + 1 == config.max_length {
+            force(logprobs, eos);
+        }
+    }
+}
+
+/// The indices and values of the `k` largest values, largest first
+fn top_k(values: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut indexed: Vec<(usize, f32)> = values.iter().copied().enumerate().collect();
+    if k < indexed.len() {
+        indexed.select_nth_unstable_by(k, |a, b| b.1.total_cmp(&a.1));
+        indexed.truncate(k);
+    }
+    indexed.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+    indexed
+}
+
+/// A sequence that is still being extended
+#[derive(Clone)]
+struct Beam {
+    tokens: Vec<Token>,
+    /// The sum of the log-probabilities of the generated tokens
+    score: f32,
+}
+
+/// The best finished sequences found so far
+struct BeamHypotheses<'a> {
+    hyps: Vec<(f32, Vec<Token>)>,
+    config: &'a GenerationConfig,
+}
+
+impl<'a> BeamHypotheses<'a> {
+    fn new(config: &'a GenerationConfig) -> Self {
+        Self {
+            hyps: Vec::with_capacity(config.num_beams + 1),
+            config,
+        }
+    }
+
+    fn normalize(&self, score: f32, len: usize) -> f32 {
+        score / (len as f32).powf(self.config.length_penalty as f32)
+    }
+
+    fn worst_score(&self) -> f32 {
+        self.hyps
+            .iter()
+            .map(|(score, _)| *score)
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    /// Adds a finished sequence, dropping the worst one when there are more than `num_beams`
+    fn add(&mut self, tokens: Vec<Token>, score: f32) {
+        let score = self.normalize(score, tokens.len());
+        if self.hyps.len() == self.config.num_beams && score <= self.worst_score() {
+            return;
+        }
+        self.hyps.push((score, tokens));
+        if self.hyps.len() > self.config.num_beams {
+            let (worst, _) = self
+                .hyps
+                .iter()
+                .enumerate()
+                .min_by(|a, b| a.1 .0.total_cmp(&b.1 .0))
+                .expect("hypotheses can't be empty after a push");
+            self.hyps.remove(worst);
+        }
+    }
+
+    /// Whether none of the running beams can improve on the finished sequences
+    fn is_done(&self, best_running_score: f32, cur_len: usize) -> bool {
+        if self.hyps.len() < self.config.num_beams {
+            return false;
+        }
+        self.config.early_stopping
+            || self.worst_score() >= self.normalize(best_running_score, cur_len)
+    }
+
+    fn best(self) -> Option<Vec<Token>> {
+        self.hyps
+            .into_iter()
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, tokens)| tokens)
+    }
+}
+
+impl BartForConditionalGeneration {
+    /// Runs the tokens the cache hasn't seen yet through the decoder and returns, for every
+    /// row of the batch, the processed log-probabilities of the token that comes next.
+    /// Every row must hold the same number of tokens.
+    fn next_logprobs(
+        &self,
+        tokenizer: &dyn Tokenizer,
+        rows: &[&[Token]],
+        encoder_hidden: &InputBatch<EncoderHidden>,
+        cache: &mut DecoderCache,
+        config: &GenerationConfig,
+        device: &Device,
+    ) -> Result<Vec<Vec<f32>>> {
+        let past_len = cache.past_len()?;
+        let new_tokens = rows
+            .iter()
+            .map(|tokens| InputSeq::from_tokens(tokens[past_len..].into()));
+        let pad = token_from_id(tokenizer, config.pad_token_id)?;
+        let logits = self.forward(
+            InputBatch::from_seqs(new_tokens, pad),
+            encoder_hidden,
+            cache,
+            device,
+        )?;
+        let logits = logits.narrow(1, logits.dim(1)? - 1, 1)?.squeeze(1)?;
+        Ok(logits
+            .to_vec2::<f32>()?
+            .iter()
+            .zip(rows)
+            .map(|(logits, tokens)| {
+                let mut logprobs = log_softmax(logits);
+                process_logprobs(tokens, &mut logprobs, config);
+                logprobs
+            })
+            .collect())
+    }
+
+    /// Generates tokens one at a time, always picking the most likely next token,
+    /// until `</s>` is generated or `config.max_length` tokens have been produced.
+    /// Returns the tokens of every row of the batch, including the decoder's start token.
+    pub fn generate_greedy(
+        &self,
+        tokenizer: &dyn Tokenizer,
+        encoder_hidden: &InputBatch<EncoderHidden>,
+        config: &GenerationConfig,
+        device: &Device,
+    ) -> Result<Vec<Vec<Token>>> {
+        let eos = token_from_id(tokenizer, config.eos_token_id)?;
+        let pad = token_from_id(tokenizer, config.pad_token_id)?;
+        let batch_size = encoder_hidden.get_last_hidden_state().dim(0)?;
+        let start = token_from_id(tokenizer, config.decoder_start_token_id)?;
+        let mut rows = vec![vec![start]; batch_size];
+        let mut done = vec![false; batch_size];
+        let mut cache = DecoderCache::default();
+
+        while rows[0].len() < config.max_length && !done.iter().all(|done| *done) {
+            let row_tokens: Vec<&[Token]> = rows.iter().map(Vec::as_slice).collect();
+            let logprobs = self.next_logprobs(
+                tokenizer,
+                &row_tokens,
+                encoder_hidden,
+                &mut cache,
+                config,
+                device,
+            )?;
+            for ((tokens, logprobs), done) in rows.iter_mut().zip(logprobs).zip(&mut done) {
+                // Finished rows are padded until every row is finished
+                let next = if *done {
+                    pad
+                } else {
+                    let (next_id, _) = top_k(&logprobs, 1)[0];
+                    debug!("Generated token {next_id}");
+                    token_from_id(tokenizer, next_id as u32)?
+                };
+                *done |= next == eos;
+                tokens.push(next);
+            }
+        }
+        for tokens in &mut rows {
+            while tokens.last() == Some(&pad) {
+                tokens.pop();
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Generates a sequence for every row of the batch with beam search, keeping the
+    /// `config.num_beams` most likely sequences of each row at every step. All the beams
+    /// of the batch are decoded together. The returned tokens include the decoder's start token.
+    pub fn generate_beam(
+        &self,
+        tokenizer: &dyn Tokenizer,
+        encoder_hidden: &InputBatch<EncoderHidden>,
+        config: &GenerationConfig,
+        device: &Device,
+    ) -> Result<Vec<Vec<Token>>> {
+        let num_beams = config.beam_count()?;
+        let eos = token_from_id(tokenizer, config.eos_token_id)?;
+        let pad = token_from_id(tokenizer, config.pad_token_id)?;
+        let batch_size = encoder_hidden.get_last_hidden_state().dim(0)?;
+        // Beam `b` of row `r` is decoded as row `r * num_beams + b`
+        let encoder_hidden = encoder_hidden.repeat_rows(num_beams)?;
+        let start = token_from_id(tokenizer, config.decoder_start_token_id)?;
+        let mut beams: Vec<Beam> = (0..batch_size * num_beams)
+            .map(|i| Beam {
+                tokens: vec![start],
+                // All beams of a row would be identical at the start, so only the first is expanded
+                score: if i % num_beams == 0 {
+                    0.0
+                } else {
+                    f32::NEG_INFINITY
+                },
+            })
+            .collect();
+        let mut hyps: Vec<_> = (0..batch_size)
+            .map(|_| BeamHypotheses::new(config))
+            .collect();
+        let mut done = vec![false; batch_size];
+        let mut cache = DecoderCache::default();
+
+        loop {
+            let beam_tokens: Vec<&[Token]> =
+                beams.iter().map(|beam| beam.tokens.as_slice()).collect();
+            let logprobs = self.next_logprobs(
+                tokenizer,
+                &beam_tokens,
+                &encoder_hidden,
+                &mut cache,
+                config,
+                device,
+            )?;
+
+            let mut next_beams = Vec::with_capacity(beams.len());
+            // The beam each of the next beams continues from
+            let mut origins = Vec::with_capacity(beams.len());
+            for (row, hyps) in hyps.iter_mut().enumerate() {
+                let row_beams = row * num_beams..(row + 1) * num_beams;
+                if done[row] {
+                    // Finished rows are padded until every row is finished
+                    for i in row_beams {
+                        let mut beam = beams[i].clone();
+                        beam.tokens.push(pad);
+                        next_beams.push(beam);
+                        origins.push(i as u32);
+                    }
+                    continue;
+                }
+
+                // Twice as many candidates as beams are kept, so enough remain after removing `</s>`
+                let mut candidates = Vec::with_capacity(num_beams * 2 * num_beams);
+                for i in row_beams {
+                    candidates.extend(
+                        top_k(&logprobs[i], 2 * num_beams)
+                            .into_iter()
+                            .map(|(id, logprob)| (beams[i].score + logprob, i, id as u32)),
+                    );
+                }
+                candidates.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+
+                let first = next_beams.len();
+                for (rank, (score, i, id)) in candidates.into_iter().enumerate() {
+                    if next_beams.len() - first == num_beams {
+                        break;
+                    }
+                    if id == eos.get_id() {
+                        // A finished sequence only counts if it is among the best `num_beams` candidates
+                        if rank < num_beams && score > f32::NEG_INFINITY {
+                            hyps.add(beams[i].tokens.clone(), score);
+                        }
+                    } else {
+                        let mut beam = beams[i].clone();
+                        beam.tokens.push(token_from_id(tokenizer, id)?);
+                        beam.score = score;
+                        next_beams.push(beam);
+                        origins.push(i as u32);
+                    }
+                }
+                let best = &next_beams[first];
+                done[row] = hyps.is_done(best.score, best.tokens.len());
+            }
+            beams = next_beams;
+            cache.reorder(&Tensor::new(origins.as_slice(), device)?)?;
+
+            let cur_len = beams[0].tokens.len();
+            debug!("Beam search reached length {cur_len}");
+            if done.iter().all(|done| *done) {
+                break;
+            }
+            if cur_len >= config.max_length {
+                for (row, hyps) in hyps.iter_mut().enumerate() {
+                    if !done[row] {
+                        for beam in &beams[row * num_beams..(row + 1) * num_beams] {
+                            hyps.add(beam.tokens.clone(), beam.score);
+                        }
+                    }
+                }
+                break;
+            }
+        }
+
+        hyps.into_iter()
+            .map(|hyps| {
+                let mut tokens = hyps
+                    .best()
+                    .ok_or_else(|| BartError::Config("beam search found no sequence".into()))?;
+                if tokens.len() < config.max_length {
+                    tokens.push(eos);
+                }
+                Ok(tokens)
+            })
+            .collect()
+    }
+}
+
+impl InputSeq<RawText> {
+    /// Summarizes the text and returns the summary as a string.
+    /// Uses beam search unless `config.num_beams` is 1, in which case decoding is greedy.
+    /// `overflow` decides what happens to text that doesn't fit in BART's context. The summaries
+    /// of the windows it may be split into are joined.
+    pub fn summarize(
+        self,
+        tokenizer: &dyn Tokenizer,
+        model: &BartForConditionalGeneration,
+        config: &GenerationConfig,
+        overflow: Overflow,
+        device: &Device,
+    ) -> Result<Box<str>> {
+        let seq = self.tokenize(tokenizer);
+        let truncation = match overflow {
+            Overflow::Truncate(truncation) => truncation,
+            Overflow::Windows { stride } => {
+                let pad = token_from_id(tokenizer, config.pad_token_id)?;
+                let windows = seq.format_windows_for_bart(stride)?;
+                for (index, window) in windows.iter().enumerate() {
+                    let offsets = window.get_offsets().unwrap_or_default();
+                    let mut text_offsets = offsets.iter().filter(|offset| !offset.special);
+                    let first = text_offsets.next();
+                    if let (Some(first), Some(last)) = (first, text_offsets.next_back().or(first)) {
+                        debug!(
+                            "Window {index} covers bytes {}..{} of the text",
+                            first.start, last.end
+                        );
+                    }
+                }
+                let windows = InputBatch::from_seqs(windows, pad);
+                let summaries = windows.summarize(tokenizer, model, config, device)?;
+                return Ok(summaries.join(" ").into());
+            }
+        };
+        let input = seq.format_for_bart(truncation)?.into_batch();
+        let mut summaries = input.summarize(tokenizer, model, config, device)?;
+        summaries
+            .pop()
+            .ok_or_else(|| BartError::Generation("a batch of one sequence gave no summary".into()))
+    }
+}
+
+impl InputBatch<BatchTokens> {
+    /// Summarizes every sequence of the batch in one pass, returning the summaries in order
+    pub fn summarize(
+        self,
+        tokenizer: &dyn Tokenizer,
+        model: &BartForConditionalGeneration,
+        config: &GenerationConfig,
+        device: &Device,
+    ) -> Result<Vec<Box<str>>> {
+        let model_vocab_size = model.vocab_size()?;
+        if tokenizer.vocab_size() > model_vocab_size {
+            return Err(BartError::Tokenizer(format!(
+                "the tokenizer has {} tokens but the model only embeds {model_vocab_size}; \
+                 call fit_tokenizer after adding tokens",
+                tokenizer.vocab_size()
+            )));
+        }
+        let encoded = model.encode(self, device)?;
+        let rows = if config.beam_count()? > 1 {
+            model.generate_beam(tokenizer, &encoded, config, device)?
+        } else {
+            model.generate_greedy(tokenizer, &encoded, config, device)?
+        };
+        let rows: Vec<&[Token]> = rows.iter().map(Vec::as_slice).collect();
+        Ok(tokenizer
+            .decode_batch(&rows, true, true)
+            .iter()
+            .map(|summary| summary.trim().into())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Truncation;
+    use crate::tokenizer::WordPieceTokenizer;
+
+    #[test]
+    fn summarizes_text() {
+        let model_path = "bart-large-cnn/bart-large-cnn_f16.gguf";
+        let mut tensors = crate::tensors::BartTensors::new(&model_path).unwrap();
+        let device = candle_core::Device::new_metal(0).unwrap();
+        let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
+        let model = BartForConditionalGeneration::new(&mut tensors, &device).unwrap();
+        let config = GenerationConfig::new("bart-large-cnn/generation_config.json").unwrap();
+
+        let input_seq = InputSeq::new("The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration".into());
+        let truncate = Overflow::Truncate(Truncation::default());
+        let summary = input_seq
+            .clone()
+            .summarize(&tokenizer, &model, &config, truncate, &device)
+            .unwrap();
+        println!("{summary}");
+        assert!(!summary.is_empty());
+        // The text fits in a single window, which is summarized like the whole text
+        let windows = Overflow::Windows { stride: 128 };
+        let window_summary = input_seq
+            .summarize(&tokenizer, &model, &config, windows, &device)
+            .unwrap();
+        assert_eq!(window_summary, summary);
+
+        let texts = [
+            "The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration",
+            "We propose a new simple network architecture, the Transformer, based solely on attention mechanisms",
+        ];
+        let summaries = InputBatch::new(texts.map(Into::into), &tokenizer, Truncation::default())
+            .unwrap()
+            .summarize(&tokenizer, &model, &config, &device)
+            .unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert!(summaries.iter().all(|summary| !summary.is_empty()));
+    }
+
+    #[test]
+    fn rejects_zero_beams() {
+        let config = GenerationConfig {
+            num_beams: 0,
+            ..GenerationConfig::default()
+        };
+        assert!(matches!(config.beam_count(), Err(BartError::Config(_))));
+        assert!(matches!(
+            GenerationConfig::from_json_str(r#"{"num_beams": 0}"#),
+            Err(BartError::Config(_))
+        ));
+        assert_eq!(GenerationConfig::default().beam_count().unwrap(), 4);
+    }
+
+    #[test]
+    fn restricts_logprobs() {
+        let config = GenerationConfig::default();
+        let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
+        let tokens = [2, 0, 100, 200, 300, 100, 200]
+            .map(|id| Token::new(&tokenizer, id).unwrap())
+            .to_vec();
+
+        let mut logprobs = vec![0.0; 1000];
+        process_logprobs(&tokens, &mut logprobs, &config);
+        // Generating 300 would repeat the trigram "100 200 300"
+        assert_eq!(logprobs[300], f32::NEG_INFINITY);
+        // The summary is shorter than `min_length`, so it can't end yet
+        assert_eq!(logprobs[2], f32::NEG_INFINITY);
+        assert_eq!(logprobs[400], 0.0);
+
+        // `<s>` is forced right after the decoder's start token
+        let mut logprobs = vec![0.0; 1000];
+        process_logprobs(&tokens[..1], &mut logprobs, &config);
+        assert_eq!(logprobs[0], 0.0);
+        assert!(logprobs[1..].iter().all(|x| *x == f32::NEG_INFINITY));
+    }
+}
@@ -1,11 +1,112 @@
-use crate::tokenizer::Token;
-use crate::WordPieceTokenizer;
+use crate::error::{BartError, Result};
+use crate::layer_norm::LayerNorm;
+use crate::tokenizer::{SpecialTokens, Token, TokenOffset, Tokenizer};
 
 use tracing::debug;
 use tracing::warn;
 
 use candle_core::Tensor;
-pub const BART_MAX_SEQ_LEN: usize = 1026;
+use std::str::FromStr;
+/// The longest sequence BART can process, in tokens
+pub const BART_MAX_SEQ_LEN: usize = 1024;
+/// BART's positional embeddings are offset by 2, so the embedding of position `i` is row `i + 2`
+pub const BART_POS_OFFSET: usize = 2;
+
+/// How to shorten inputs that don't fit in BART's context, like the `truncation` option of HF's tokenizers.
+/// For a single sequence, [`Truncation::LongestFirst`] and [`Truncation::OnlyFirst`] both drop its last tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Truncation {
+    /// Drops the last tokens of the longer sequence of a pair until both fit
+    #[default]
+    LongestFirst,
+    /// Drops the last tokens of the first sequence of a pair only
+    OnlyFirst,
+    /// Keeps the first `head` tokens of every sequence and drops tokens from the middle,
+    /// so the end of a long article is kept as well. Pairs are shortened like with `LongestFirst`.
+    HeadTail { head: usize },
+}
+
+impl FromStr for Truncation {
+    type Err = BartError;
+
+    /// Parses HF's `longest_first` and `only_first`, or `head_tail:<head>`
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "longest_first" => Ok(Self::LongestFirst),
+            "only_first" => Ok(Self::OnlyFirst),
+            _ => {
+                let head = name
+                    .strip_prefix("head_tail:")
+                    .and_then(|head| head.parse().ok())
+                    .ok_or_else(|| {
+                        BartError::Config(format!("unknown truncation strategy {name}"))
+                    })?;
+                Ok(Self::HeadTail { head })
+            }
+        }
+    }
+}
+
+/// What to do with text that doesn't fit in BART's context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Shortens the text with the given strategy
+    Truncate(Truncation),
+    /// Splits the text into windows that share `stride` tokens, like HF's `return_overflowing_tokens`
+    Windows { stride: usize },
+}
+
+/// The lengths a pair of sequences must be cut to so that they fit in `max_len` tokens together,
+/// or `None` if that isn't possible without cutting the second sequence under `OnlyFirst`
+fn truncated_lens(
+    first: usize,
+    second: usize,
+    max_len: usize,
+    truncation: Truncation,
+) -> Option<(usize, usize)> {
+    if first + second <= max_len {
+        return Some((first, second));
+    }
+    match truncation {
+        Truncation::OnlyFirst => Some((max_len.checked_sub(second)?, second)),
+        Truncation::LongestFirst | Truncation::HeadTail { .. } => {
+            // Removing a token from the longer sequence at a time keeps the shorter one whole
+            // if it fits in half of the context, and splits the context evenly otherwise
+            let shorter = first.min(second);
+            if shorter <= max_len / 2 {
+                let longer = max_len - shorter;
+                Some((first.min(longer), second.min(longer)))
+            } else {
+                Some((max_len - max_len / 2, max_len / 2))
+            }
+        }
+    }
+}
+
+/// Shortens `pieces` to `len`, dropping tokens from the end, or from the middle with `HeadTail`
+fn cut<T>(pieces: &mut Vec<T>, len: usize, truncation: Truncation) {
+    if pieces.len() <= len {
+        return;
+    }
+    match truncation {
+        Truncation::HeadTail { head } => {
+            let head = head.min(len);
+            let tail = len - head;
+            pieces.drain(head..pieces.len() - tail);
+        }
+        Truncation::LongestFirst | Truncation::OnlyFirst => pieces.truncate(len),
+    }
+}
+
+/// The first tokens of the windows of `size` tokens that cover `len` tokens,
+/// each one starting `size - stride` tokens after the one before
+fn window_starts(len: usize, size: usize, stride: usize) -> Vec<usize> {
+    let mut starts = vec![0];
+    while starts[starts.len() - 1] + size < len {
+        starts.push(starts[starts.len() - 1] + size - stride);
+    }
+    starts
+}
 
 /// The state of an input sequence
 #[derive(Clone, Default)]
@@ -13,11 +114,14 @@ pub struct InputSeq<T: InputData> {
     pub state: T,
 }
 
-pub trait InputData{}
+/// The state of several input sequences that are processed together.
+/// Once embedded, every stage holds `(batch, seq_len, ...)` tensors.
+#[derive(Clone)]
+pub struct InputBatch<T: InputData> {
+    pub state: T,
+}
 
-#[derive(Default, Clone)]
-pub struct Empty {}
-impl InputData for Empty {}
+pub trait InputData{}
 
 #[derive(Default, Clone)]
 pub struct RawText(Box<str>);
@@ -26,146 +130,499 @@ impl InputData for RawText {}
 #[derive(Default, Clone)]
 pub struct Tokenized {
     tokens: Box<[Token]>,
-    tokenizer: WordPieceTokenizer,
+    /// The range of the raw text every token was produced from
+    offsets: Box<[TokenOffset]>,
+    /// The special tokens of the tokenizer, which formatting surrounds the tokens with
+    special_tokens: SpecialTokens,
 }
 impl InputData for Tokenized {}
 
 #[derive(Default, Clone)]
-pub struct BartTokens(Box<[Token]>);
+pub struct BartTokens {
+    tokens: Box<[Token]>,
+    /// 1 for every real token and 0 for every padding token
+    attention_mask: Box<[u8]>,
+    /// The range of the raw text every token was produced from, if the tokens come from text
+    offsets: Option<Box<[TokenOffset]>>,
+}
 impl InputData for BartTokens {}
 
+/// The formatted tokens of every sequence in a batch, padded to the length of the longest one
+#[derive(Default, Clone)]
+pub struct BatchTokens(Box<[InputSeq<BartTokens>]>);
+impl InputData for BatchTokens {}
+
 #[derive(Clone)]
-pub struct TokenEmbeddings(Tensor);
+pub struct TokenEmbeddings {
+    embeds: Tensor,
+    attention_mask: Tensor,
+}
 impl Default for TokenEmbeddings {
     fn default() -> Self {
         let device = candle_core::Device::Cpu;
-        Self(Tensor::new::<&[f32; 0]>(&[], &device).unwrap())
+        Self {
+            embeds: Tensor::new::<&[f32; 0]>(&[], &device).unwrap(),
+            attention_mask: Tensor::new::<&[u8; 0]>(&[], &device).unwrap(),
+        }
     }
 }
 impl InputData for TokenEmbeddings {}
 
 #[derive(Clone)]
-pub struct PositionedEmbeddings(Tensor);
+pub struct PositionedEmbeddings {
+    embeds: Tensor,
+    attention_mask: Tensor,
+}
 impl Default for PositionedEmbeddings {
     fn default() -> Self {
         let device = candle_core::Device::Cpu;
-        Self(Tensor::new::<&[f32; 0]>(&[], &device).unwrap())
+        Self {
+            embeds: Tensor::new::<&[f32; 0]>(&[], &device).unwrap(),
+            attention_mask: Tensor::new::<&[u8; 0]>(&[], &device).unwrap(),
+        }
     }
 }
 impl InputData for PositionedEmbeddings {}
 
-impl InputSeq<Empty> {
-    pub fn new(text: Box<str>) -> InputSeq<RawText> {
+impl InputSeq<BartTokens> {
+    /// Starts from tokens that are already formatted for BART, such as the decoder's input
+    pub fn from_tokens(tokens: Box<[Token]>) -> InputSeq<BartTokens> {
+        let attention_mask = vec![1; tokens.len()].into_boxed_slice();
         InputSeq {
-            state: RawText(text),
-            ..InputSeq::default()
+            state: BartTokens {
+                tokens,
+                attention_mask,
+                offsets: None,
+            },
+        }
+    }
+}
+
+impl InputBatch<BatchTokens> {
+    /// Tokenizes every text on all CPU cores and formats it, padding them to the longest one
+    /// in the batch. The sequences are in the same order as the texts, and the ones that
+    /// don't fit in BART's context are shortened with `truncation`.
+    pub fn new<I: IntoIterator<Item = Box<str>>>(
+        texts: I,
+        tokenizer: &dyn Tokenizer,
+        truncation: Truncation,
+    ) -> Result<InputBatch<BatchTokens>> {
+        let pad = tokenizer
+            .get_special_tokens()
+            .pad
+            .ok_or_else(|| BartError::Tokenizer("<pad> not found in vocab".into()))?;
+        let texts: Vec<_> = texts.into_iter().collect();
+        let texts: Vec<&str> = texts.iter().map(AsRef::as_ref).collect();
+        let seqs = tokenizer
+            .tokenize_batch(&texts)
+            .into_iter()
+            .map(|pieces| InputSeq::from_pieces(pieces, tokenizer).format_for_bart(truncation))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::from_seqs(seqs, pad))
+    }
+
+    /// Batches sequences that are already formatted for BART, padding them with `pad`
+    pub fn from_seqs<I: IntoIterator<Item = InputSeq<BartTokens>>>(
+        seqs: I,
+        pad: Token,
+    ) -> InputBatch<BatchTokens> {
+        let seqs: Vec<_> = seqs.into_iter().collect();
+        let len = seqs
+            .iter()
+            .map(|seq| seq.get_tokens().len())
+            .max()
+            .unwrap_or(0);
+        debug!("Padding {} sequences to {len} tokens", seqs.len());
+        InputBatch {
+            state: BatchTokens(seqs.into_iter().map(|seq| seq.pad_to(len, pad)).collect()),
         }
     }
 }
 
 impl InputSeq<RawText> {
-    pub fn tokenize(self, tokenizer: &WordPieceTokenizer) -> InputSeq<Tokenized> {
-        let mut tokens = Vec::new();
-        let text = self.state.0.replace(' ', "Ġ");
-        let mut start = 0;
-        while start < text.len() {
-            let longest = tokenizer
-                .get_vocab()
-                .iter()
-                .filter(|(_key, value)| text[start..].starts_with(*value))
-                .max_by_key(|(_key, value)| value.len());
-
-            if let Some(longest) = longest {
-                tokens.push(Token::new(tokenizer, *longest.0).unwrap());
-                start += longest.1.len();
-            } else {
-                warn!("Unrecognized text sequence. Inserting <unk> token");
-                let unk = tokenizer
-                    .get_vocab()
-                    .clone()
-                    .into_iter()
-                    .find(|(_, value)| value == "<unk>")
-                    .unwrap();
-                tokens.push(Token::new(tokenizer, unk.0).unwrap());
-                start += 1;
-            }
+    pub fn new(text: Box<str>) -> Self {
+        InputSeq {
+            state: RawText(text),
         }
+    }
+
+    /// Splits the text into tokens with the given tokenizer
+    pub fn tokenize(self, tokenizer: &dyn Tokenizer) -> InputSeq<Tokenized> {
+        InputSeq::from_pieces(tokenizer.tokenize_with_offsets(&self.state.0), tokenizer)
+    }
+}
+
+impl InputSeq<Tokenized> {
+    /// Wraps the tokens `tokenizer` split a text into, together with their offsets
+    fn from_pieces(pieces: Vec<(Token, TokenOffset)>, tokenizer: &dyn Tokenizer) -> Self {
+        let (tokens, offsets): (Vec<_>, Vec<_>) = pieces.into_iter().unzip();
         debug!("Tokenized text into {} tokens", tokens.len());
         InputSeq {
             state: Tokenized {
                 tokens: tokens.into(),
-                tokenizer: tokenizer.clone(),
+                offsets: offsets.into(),
+                special_tokens: tokenizer.get_special_tokens().clone(),
             },
-            ..InputSeq::default()
         }
     }
-}
 
-impl InputSeq<Tokenized> {
-    /// Formats the given tokens in the way BART was trained to process them
-    pub fn format_for_bart(self) -> InputSeq<BartTokens> {
+    pub fn get_tokens(&self) -> &[Token] {
+        &self.state.tokens
+    }
+
+    /// The byte range of the raw text every token was produced from
+    pub fn get_offsets(&self) -> &[TokenOffset] {
+        &self.state.offsets
+    }
+
+    /// The tokens together with their offsets
+    fn pieces(&self) -> Vec<(Token, TokenOffset)> {
+        self.get_tokens()
+            .iter()
+            .copied()
+            .zip(self.get_offsets().iter().copied())
+            .collect()
+    }
+
+    /// Surrounds every segment with `<s>` and `</s>`, merging the `</s><s>` between
+    /// two segments into `</s></s>` the way BART's tokenizer joins pairs
+    fn add_special_tokens(
+        &self,
+        segments: &[&[(Token, TokenOffset)]],
+    ) -> Result<InputSeq<BartTokens>> {
+        let special_tokens = &self.state.special_tokens;
+        let bos = special_tokens
+            .bos
+            .ok_or_else(|| BartError::Tokenizer("<s> not found in vocab".into()))?;
+        let eos = special_tokens
+            .eos
+            .ok_or_else(|| BartError::Tokenizer("</s> not found in vocab".into()))?;
+
+        let mut pieces = vec![(bos, TokenOffset::special())];
+        for (i, segment) in segments.iter().enumerate() {
+            if i > 0 {
+                pieces.push((eos, TokenOffset::special()));
+            }
+            pieces.extend_from_slice(segment);
+            pieces.push((eos, TokenOffset::special()));
+        }
+        let (tokens, offsets): (Vec<_>, Vec<_>) = pieces.into_iter().unzip();
+
+        let mut seq = InputSeq::from_tokens(tokens.into_boxed_slice());
+        seq.state.offsets = Some(offsets.into());
+        Ok(seq)
+    }
+
+    /// Formats the given tokens in the way BART was trained to process them.
+    /// `truncation` chooses the tokens that are dropped when the sequence doesn't fit in BART's context.
+    /// Fails if the tokenizer has no `<s>` or `</s>` token.
+    pub fn format_for_bart(self, truncation: Truncation) -> Result<InputSeq<BartTokens>> {
         debug!("Formatting input token sequence");
-        let mut tokens = self.state.tokens.to_vec();
-        tokens.insert(
-            0,
-            Token::from_substr(&self.state.tokenizer, "<s>").expect("<s> not found in vocab"),
-        );
-        tokens.push(
-            Token::from_substr(&self.state.tokenizer, "</s>").expect("</s> not found in vocab"),
-        );
-        let padding_length = BART_MAX_SEQ_LEN - tokens.len();
-        tokens.reserve(padding_length);
-        for _ in 0..padding_length {
-            tokens.push(
-                Token::from_substr(&self.state.tokenizer, "<pad>")
-                    .expect("<pad> not found in vocab"),
+        let mut pieces = self.pieces();
+        // Leave room for `<s>` and `</s>`
+        let max_len = BART_MAX_SEQ_LEN - 2;
+        if pieces.len() > max_len {
+            warn!(
+                "Truncating input of {} tokens to BART's maximum of {BART_MAX_SEQ_LEN}",
+                pieces.len() + 2
             );
+            cut(&mut pieces, max_len, truncation);
         }
+        self.add_special_tokens(&[&pieces])
+    }
 
-        InputSeq {
-            state: BartTokens(tokens.into_boxed_slice()),
-            ..Default::default()
+    /// Formats a pair of sequences as `<s> first </s></s> second </s>`.
+    /// Fails if `truncation` can't make the pair fit in BART's context, which happens
+    /// with [`Truncation::OnlyFirst`] when the second sequence alone is too long.
+    // The summarizer formats single texts, so only the tests format pairs so far
+    #[allow(dead_code)]
+    pub fn format_pair_for_bart(
+        self,
+        second: InputSeq<Tokenized>,
+        truncation: Truncation,
+    ) -> Result<InputSeq<BartTokens>> {
+        debug!("Formatting input token sequence pair");
+        let mut first_pieces = self.pieces();
+        let mut second_pieces = second.pieces();
+        // Leave room for `<s>`, `</s></s>` and `</s>`
+        let max_len = BART_MAX_SEQ_LEN - 4;
+        let (first_len, second_len) =
+            truncated_lens(first_pieces.len(), second_pieces.len(), max_len, truncation)
+                .ok_or_else(|| {
+                    BartError::Config(format!(
+                        "the second sequence of {} tokens doesn't fit in BART's context",
+                        second_pieces.len()
+                    ))
+                })?;
+        if first_len + second_len < first_pieces.len() + second_pieces.len() {
+            warn!(
+                "Truncating input pair of {} and {} tokens to {first_len} and {second_len} tokens",
+                first_pieces.len(),
+                second_pieces.len()
+            );
+        }
+        cut(&mut first_pieces, first_len, truncation);
+        cut(&mut second_pieces, second_len, truncation);
+        self.add_special_tokens(&[&first_pieces, &second_pieces])
+    }
+
+    /// Splits a sequence into overlapping windows that each fit in BART's context
+    /// instead of truncating it, like HF's `return_overflowing_tokens`.
+    /// Consecutive windows share `stride` tokens. Fails if `stride` leaves no room for new tokens.
+    pub fn format_windows_for_bart(self, stride: usize) -> Result<Vec<InputSeq<BartTokens>>> {
+        let size = BART_MAX_SEQ_LEN - 2;
+        if stride >= size {
+            return Err(BartError::Config(format!(
+                "a stride of {stride} tokens leaves no new tokens in windows of {size}"
+            )));
         }
+        let pieces = self.pieces();
+        let starts = window_starts(pieces.len(), size, stride);
+        debug!(
+            "Splitting {} tokens into {} windows",
+            pieces.len(),
+            starts.len()
+        );
+        starts
+            .into_iter()
+            .map(|start| {
+                let end = (start + size).min(pieces.len());
+                self.add_special_tokens(&[&pieces[start..end]])
+            })
+            .collect()
     }
 }
 
 impl InputSeq<BartTokens> {
-    pub fn embed(
-        self,
-        embed_tensor: &candle_core::Tensor,
-    ) -> Result<InputSeq<TokenEmbeddings>, candle_core::Error> {
+    pub fn get_tokens(&self) -> &[Token] {
+        &self.state.tokens
+    }
+
+    pub fn get_attention_mask(&self) -> &[u8] {
+        &self.state.attention_mask
+    }
+
+    /// The byte range of the raw text every token was produced from, like HF's
+    /// `return_offsets_mapping`. `None` when the sequence was built from tokens.
+    pub fn get_offsets(&self) -> Option<&[TokenOffset]> {
+        self.state.offsets.as_deref()
+    }
+
+    /// Appends `pad` tokens until the sequence is `len` tokens long, masking them out of attention.
+    /// This lets sequences of different lengths be processed together.
+    pub fn pad_to(self, len: usize, pad: Token) -> Self {
+        let BartTokens {
+            tokens,
+            attention_mask,
+            offsets,
+        } = self.state;
+        let mut tokens = tokens.to_vec();
+        let mut attention_mask = attention_mask.to_vec();
+        let len = len.max(tokens.len());
+        tokens.resize(len, pad);
+        attention_mask.resize(len, 0);
+        let offsets = offsets.map(|offsets| {
+            let mut offsets = offsets.to_vec();
+            offsets.resize(len, TokenOffset::special());
+            offsets.into()
+        });
+        InputSeq {
+            state: BartTokens {
+                tokens: tokens.into(),
+                attention_mask: attention_mask.into(),
+                offsets,
+            },
+        }
+    }
+
+    /// Turns the sequence into a batch of one
+    pub fn into_batch(self) -> InputBatch<BatchTokens> {
+        InputBatch {
+            state: BatchTokens([self].into()),
+        }
+    }
+}
+
+impl InputBatch<BatchTokens> {
+    pub fn get_seqs(&self) -> &[InputSeq<BartTokens>] {
+        &self.state.0
+    }
+
+    /// Looks up the embedding of every token, giving a `(batch, seq_len, hidden)` tensor
+    /// together with a `(batch, seq_len)` attention mask
+    pub fn embed(self, embed_tensor: &candle_core::Tensor) -> Result<InputBatch<TokenEmbeddings>> {
         debug!("Assigning token embeddings");
+        let seqs = self.get_seqs();
+        let batch_size = seqs.len();
+        let seq_len = seqs.first().map_or(0, |seq| seq.get_tokens().len());
 
         let indices = candle_core::Tensor::from_vec(
-            self.state.0.iter().map(|token| token.get_id() as u32).collect(),
-            (self.state.0.len(),),
-            embed_tensor.device()
+            seqs.iter()
+                .flat_map(|seq| seq.get_tokens())
+                .map(|token| token.get_id())
+                .collect(),
+            (batch_size * seq_len,),
+            embed_tensor.device(),
         )?;
-
+        let hidden_size = embed_tensor.dim(1)?;
         let embeds = embed_tensor.index_select(&indices, 0)?;
+        let embeds = embeds.reshape((batch_size, seq_len, hidden_size))?;
+        let attention_mask = Tensor::from_vec(
+            seqs.iter()
+                .flat_map(|seq| seq.get_attention_mask())
+                .copied()
+                .collect(),
+            (batch_size, seq_len),
+            embed_tensor.device(),
+        )?;
 
-        Ok(InputSeq {
-            state: TokenEmbeddings(embeds),
-            ..Default::default()
+        Ok(InputBatch {
+            state: TokenEmbeddings {
+                embeds,
+                attention_mask,
+            },
         })
     }
 }
 
-impl InputSeq<TokenEmbeddings> {
+impl InputBatch<TokenEmbeddings> {
+    /// Adds the positional embeddings and normalizes the sum with `layernorm_embedding`.
+    /// `past_len` is the position of the first token, which is non-zero when
+    /// the decoder continues from the positions stored in its cache.
     pub fn add_pos_embeds(
         self,
         pos_embeds: &candle_core::Tensor,
-    ) -> Result<InputSeq<PositionedEmbeddings>, candle_core::Error> {
-        let comb_embeds = (&self.state.0 + pos_embeds)?;
+        past_len: usize,
+        layernorm_embedding: &LayerNorm,
+    ) -> Result<InputBatch<PositionedEmbeddings>> {
+        let seq_len = self.state.embeds.dim(1)?;
+        let pos_embeds = pos_embeds.narrow(0, BART_POS_OFFSET + past_len, seq_len)?;
+        let comb_embeds =
+            layernorm_embedding.forward(&self.state.embeds.broadcast_add(&pos_embeds)?)?;
 
-        Ok(InputSeq {
-            state: PositionedEmbeddings(comb_embeds),
-            ..Default::default()
+        Ok(InputBatch {
+            state: PositionedEmbeddings {
+                embeds: comb_embeds,
+                attention_mask: self.state.attention_mask,
+            },
         })
     }
 }
-impl InputSeq<PositionedEmbeddings> {
+impl InputBatch<PositionedEmbeddings> {
     pub fn get_embeds(&self) -> &candle_core::Tensor {
-        &self.state.0
+        &self.state.embeds
+    }
+
+    /// A `(batch, seq_len)` tensor with 1 for every real token and 0 for every padding token
+    pub fn get_attention_mask(&self) -> &candle_core::Tensor {
+        &self.state.attention_mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::fixtures::{tokenizer_from_json, HI_TOKENIZER_JSON};
+
+    #[test]
+    fn batches_in_input_order() {
+        let tokenizer = tokenizer_from_json(HI_TOKENIZER_JSON);
+
+        let texts = ["hi hi", "hi", "hi hi hi", ""];
+        let batch =
+            InputBatch::new(texts.map(Into::into), &tokenizer, Truncation::LongestFirst).unwrap();
+        let ids: Vec<Vec<u32>> = batch
+            .get_seqs()
+            .iter()
+            .map(|seq| seq.get_tokens().iter().map(Token::get_id).collect())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                vec![0, 7, 8, 2, 1],
+                vec![0, 7, 2, 1, 1],
+                vec![0, 7, 8, 8, 2],
+                vec![0, 2, 1, 1, 1]
+            ]
+        );
+        assert_eq!(batch.get_seqs()[1].get_attention_mask(), [1, 1, 1, 0, 0]);
+        let offsets = batch.get_seqs()[1].get_offsets().unwrap();
+        assert_eq!((offsets[1].start, offsets[1].end), (0, 2));
+        assert!(offsets[2].special && offsets[4].special);
+        assert_eq!(
+            tokenizer.tokenize_batch(&texts)[2].len(),
+            tokenizer.tokenize(texts[2]).len()
+        );
+    }
+
+    #[test]
+    fn formats_pairs() {
+        let tokenizer = tokenizer_from_json(HI_TOKENIZER_JSON);
+        let tokenize = |text: &str| InputSeq::new(text.into()).tokenize(&tokenizer);
+
+        let pair = tokenize("hi")
+            .format_pair_for_bart(tokenize("hi"), Truncation::OnlyFirst)
+            .unwrap();
+        let ids: Vec<u32> = pair.get_tokens().iter().map(Token::get_id).collect();
+        assert_eq!(ids, [0, 7, 2, 2, 7, 2]);
+
+        let long = vec!["hi"; BART_MAX_SEQ_LEN].join(" ");
+        let error = tokenize("hi").format_pair_for_bart(tokenize(&long), Truncation::OnlyFirst);
+        assert!(matches!(error, Err(BartError::Config(_))));
+        let pair = tokenize("hi")
+            .format_pair_for_bart(tokenize(&long), Truncation::LongestFirst)
+            .unwrap();
+        assert_eq!(pair.get_tokens().len(), BART_MAX_SEQ_LEN);
+    }
+
+    #[test]
+    fn truncates_pairs() {
+        let strategies = [
+            Truncation::LongestFirst,
+            Truncation::OnlyFirst,
+            Truncation::HeadTail { head: 2 },
+        ];
+        for truncation in strategies {
+            assert_eq!(truncated_lens(3, 4, 8, truncation), Some((3, 4)));
+        }
+        assert_eq!(
+            truncated_lens(10, 3, 8, Truncation::LongestFirst),
+            Some((5, 3))
+        );
+        assert_eq!(
+            truncated_lens(6, 5, 7, Truncation::LongestFirst),
+            Some((4, 3))
+        );
+        assert_eq!(truncated_lens(6, 5, 8, Truncation::OnlyFirst), Some((3, 5)));
+        assert_eq!(truncated_lens(2, 9, 8, Truncation::OnlyFirst), None);
+    }
+
+    #[test]
+    fn parses_truncation() {
+        assert_eq!("only_first".parse().ok(), Some(Truncation::OnlyFirst));
+        assert_eq!(
+            "head_tail:128".parse().ok(),
+            Some(Truncation::HeadTail { head: 128 })
+        );
+        assert!(matches!(
+            "end".parse::<Truncation>(),
+            Err(BartError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn cuts_head_and_tail() {
+        let mut pieces: Vec<_> = (0..10).collect();
+        cut(&mut pieces, 5, Truncation::HeadTail { head: 2 });
+        assert_eq!(pieces, [0, 1, 7, 8, 9]);
+        cut(&mut pieces, 3, Truncation::LongestFirst);
+        assert_eq!(pieces, [0, 1, 7]);
+    }
+
+    #[test]
+    fn splits_overlapping_windows() {
+        assert_eq!(window_starts(10, 4, 1), [0, 3, 6]);
+        assert_eq!(window_starts(11, 4, 1), [0, 3, 6, 9]);
+        assert_eq!(window_starts(3, 4, 1), [0]);
     }
 }
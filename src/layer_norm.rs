@@ -0,0 +1,89 @@
+use candle_core::{quantized::QTensor, DType, Device, Tensor, D};
+
+use crate::{
+    bart_tensor_type::{TensorName, TensorType},
+    error::{BartError, Result},
+    tensors::WeightSource,
+};
+
+/// The epsilon BART adds to the variance to avoid dividing by zero
+pub const BART_LAYER_NORM_EPS: f64 = 1e-5;
+
+/// Normalizes every row to zero mean and unit variance, then applies a learned scale and shift
+pub struct LayerNorm {
+    weight: QTensor,
+    bias: QTensor,
+}
+
+impl LayerNorm {
+    /// Loads the weight and bias of a layer norm, where `tensor_name` maps each
+    /// tensor type to the full name of the tensor in the model file
+    pub fn new<F: Fn(TensorType) -> TensorName>(
+        tensor_name: F,
+        tensors: &mut dyn WeightSource,
+        device: &Device,
+    ) -> Result<Self> {
+        let weight = tensors.get_tensor(tensor_name(TensorType::Weight), device)?;
+        let bias = tensors.get_tensor(tensor_name(TensorType::Bias), device)?;
+        Ok(LayerNorm { weight, bias })
+    }
+
+    /// Normalizes over the hidden dimension. The statistics are computed in f32
+    /// and the result is returned in the dtype of `input`.
+    pub fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let hidden_size = self.weight.shape().elem_count();
+        if input.dim(D::Minus1)? != hidden_size {
+            return Err(BartError::ShapeMismatch(format!(
+                "input {:?} doesn't end in the {hidden_size} normalized dimensions",
+                input.shape()
+            )));
+        }
+        let dtype = input.dtype();
+        let input = input.to_dtype(DType::F32)?;
+        let mean = input.mean_keepdim(D::Minus1)?;
+        let centered = input.broadcast_sub(&mean)?;
+        let var = centered.sqr()?.mean_keepdim(D::Minus1)?;
+        let normalized = centered.broadcast_div(&(var + BART_LAYER_NORM_EPS)?.sqrt()?)?;
+
+        let weight = self.weight.dequantize(input.device())?;
+        let bias = self.bias.dequantize(input.device())?;
+        Ok(normalized
+            .broadcast_mul(&weight)?
+            .broadcast_add(&bias)?
+            .to_dtype(dtype)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_rows() {
+        let device = Device::Cpu;
+        let ones = Tensor::ones(4, DType::F32, &device).unwrap();
+        let zeros = Tensor::zeros(4, DType::F32, &device).unwrap();
+        let layer_norm = LayerNorm {
+            weight: QTensor::quantize(&ones, candle_core::quantized::GgmlDType::F32).unwrap(),
+            bias: QTensor::quantize(&zeros, candle_core::quantized::GgmlDType::F32).unwrap(),
+        };
+
+        let input = Tensor::new(&[[1f32, 2., 3., 4.], [10., 10., 10., 10.]], &device).unwrap();
+        let out = layer_norm
+            .forward(&input)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        let expected = [-1.3416f32, -0.4472, 0.4472, 1.3416];
+        for (x, e) in out[0].iter().zip(expected) {
+            assert!((x - e).abs() < 1e-3);
+        }
+        assert!(out[1].iter().all(|x| x.abs() < 1e-3));
+
+        let input = Tensor::new(&[[1f32, 2., 3.]], &device).unwrap();
+        assert!(matches!(
+            layer_norm.forward(&input),
+            Err(BartError::ShapeMismatch(_))
+        ));
+    }
+}
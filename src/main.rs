@@ -1,21 +1,31 @@
 #![allow(clippy::boxed_local)]
 mod attn;
 mod attn_head;
+mod bart;
 mod bart_tensor_type;
+mod decoder;
+mod encoder;
+mod error;
+mod feed_forward;
+mod generation;
 mod input;
+mod layer_norm;
+mod sentencepiece;
 mod tensors;
 mod tokenizer;
 mod utils;
 
 use candle_core::Device;
-use tensors::BartTensors;
-use tokenizer::WordPieceTokenizer;
+use tensors::open_weights;
+use tokenizer::open_tokenizer;
 
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use crate::bart_tensor_type::TensorName;
-use crate::input::InputSeq;
+use crate::bart::BartForConditionalGeneration;
+use crate::error::BartError;
+use crate::generation::GenerationConfig;
+use crate::input::{InputBatch, InputSeq, Overflow, Truncation};
 
 fn main() {
     let subscriber = FmtSubscriber::builder()
@@ -28,24 +38,48 @@ fn main() {
 }
 
 fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json")?;
+    let tokenizer = open_tokenizer(&"bart-large-cnn")?;
     let model_path = "bart-large-cnn/bart-large-cnn_f16.gguf";
     info!("Reading model from {model_path}");
-    let mut tensors = BartTensors::new(&model_path)?;
+    let mut tensors = open_weights(&model_path)?;
     let device = Device::new_metal(0)?;
+    let mut model = BartForConditionalGeneration::new(tensors.as_mut(), &device)?;
+    model.fit_tokenizer(tokenizer.as_ref())?;
+    let config = GenerationConfig::new("bart-large-cnn/generation_config.json")?;
 
-    let token_embeds = tensors.get_tensor(TensorName::EmbedTokensWeights, &device);
-    let pos_embeds = tensors.get_tensor(TensorName::EmbedPositionWeights, &device);
-    let input_seq = InputSeq::new("The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration".into());
-    let input_seq = input_seq
-        .tokenize(&tokenizer)
-        .format_for_bart()
-        .embed(&token_embeds.dequantize(&device)?)? //TODO: remove dequantization
-        .add_pos_embeds(&pos_embeds.dequantize(&device)?)?;
-
-    for i in 0..input_seq.get_embeds().dim(0)? {
-        let row = input_seq.get_embeds().get(i)?;
-        println!("{:?}", &row.to_vec1::<f32>()?[..5]);
+    // `--truncation <strategy>` and `--stride <tokens>` configure how long input is cut, and
+    // every other argument is a text to summarize. Several texts are summarized as one batch
+    let mut truncation = Truncation::default();
+    let mut stride = None;
+    let mut texts: Vec<Box<str>> = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--truncation" => truncation = args.next().unwrap_or_default().parse()?,
+            "--stride" => {
+                let value = args.next().unwrap_or_default();
+                let tokens = value
+                    .parse()
+                    .map_err(|_| BartError::Config(format!("invalid stride {value}")))?;
+                stride = Some(tokens);
+            }
+            _ => texts.push(arg.into()),
+        }
+    }
+    if texts.len() > 1 {
+        let input_batch = InputBatch::new(texts, tokenizer.as_ref(), truncation)?;
+        for summary in input_batch.summarize(tokenizer.as_ref(), &model, &config, &device)? {
+            println!("{summary}");
+        }
+        return Ok(());
     }
+    let text = texts.pop().unwrap_or_else(|| "The dominant sequence transduction models are based on complex recurrent or convolutional neural networks in an encoder-decoder configuration".into());
+    let overflow = match stride {
+        Some(stride) => Overflow::Windows { stride },
+        None => Overflow::Truncate(truncation),
+    };
+    let summary =
+        InputSeq::new(text).summarize(tokenizer.as_ref(), &model, &config, overflow, &device)?;
+    println!("{summary}");
     Ok(())
 }
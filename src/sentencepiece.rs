@@ -0,0 +1,655 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use tracing::{debug, warn};
+
+use crate::error::{BartError, Result};
+use crate::tokenizer::{
+    clean_up_tokenization, AddedToken, AddedVocab, SpecialTokens, Token, TokenOffset, Tokenizer,
+};
+
+/// SentencePiece writes spaces as `▁`, so that they can be part of pieces
+const SPACE: char = '▁';
+
+/// SentencePiece scores unknown characters this far below the least likely piece
+const UNK_PENALTY: f32 = 10.0;
+
+fn invalid_data(message: impl Into<String>) -> BartError {
+    BartError::Tokenizer(message.into())
+}
+
+/// A field of a protobuf message, which `sentencepiece.bpe.model` is stored as
+enum Field<'a> {
+    Varint(u64),
+    /// None of the fields the tokenizer reads are 64-bit, so the value is skipped
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Reads the fields of a protobuf message one at a time, along with their field numbers.
+/// Only the wire format is parsed, the meaning of the fields is up to the caller.
+struct ProtoReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(invalid_data("truncated protobuf message"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("protobuf varint is too long"))
+    }
+
+    fn field(&mut self) -> Result<(u64, Field<'a>)> {
+        let key = self.varint()?;
+        let field = match key & 0x7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            wire_type => {
+                return Err(invalid_data(format!(
+                    "unsupported protobuf wire type {wire_type}"
+                )))
+            }
+        };
+        Ok((key >> 3, field))
+    }
+}
+
+impl<'a> Iterator for ProtoReader<'a> {
+    type Item = Result<(u64, Field<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        (!self.bytes.is_empty()).then(|| self.field())
+    }
+}
+
+/// What a piece of a SentencePiece model stands for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PieceKind {
+    Normal,
+    Unknown,
+    /// Pieces such as `<s>` that never come from text
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+impl PieceKind {
+    fn from_proto(kind: u64) -> Result<Self> {
+        Ok(match kind {
+            1 => Self::Normal,
+            2 => Self::Unknown,
+            3 => Self::Control,
+            4 => Self::UserDefined,
+            5 => Self::Unused,
+            6 => Self::Byte,
+            _ => return Err(invalid_data(format!("unknown piece type {kind}"))),
+        })
+    }
+
+    /// Whether text can be split into pieces of this kind
+    fn is_text(self) -> bool {
+        matches!(self, Self::Normal | Self::UserDefined)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: PieceKind,
+}
+
+impl Piece {
+    fn control(piece: &str) -> Self {
+        Self {
+            piece: piece.to_owned(),
+            score: 0.0,
+            kind: PieceKind::Control,
+        }
+    }
+}
+
+/// The algorithm the model was trained with, which decides how words are split into pieces
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ModelType {
+    /// Picks the split with the highest total score
+    Unigram,
+    /// Merges the adjacent pair with the highest score until no pair is in the vocab
+    Bpe,
+}
+
+/// The parts of a SentencePiece `ModelProto` that the tokenizer is built from
+struct ModelProto {
+    pieces: Vec<Piece>,
+    model_type: ModelType,
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+}
+
+impl ModelProto {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut model = ModelProto {
+            pieces: Vec::new(),
+            model_type: ModelType::Unigram,
+            add_dummy_prefix: true,
+            remove_extra_whitespaces: true,
+        };
+        for field in ProtoReader::new(bytes) {
+            match field? {
+                (1, Field::Bytes(piece)) => model.pieces.push(Self::parse_piece(piece)?),
+                // TrainerSpec
+                (2, Field::Bytes(spec)) => {
+                    for field in ProtoReader::new(spec) {
+                        if let (3, Field::Varint(model_type)) = field? {
+                            model.model_type = match model_type {
+                                1 => ModelType::Unigram,
+                                2 => ModelType::Bpe,
+                                _ => {
+                                    return Err(invalid_data(format!(
+                                        "unsupported SentencePiece model type {model_type}"
+                                    )))
+                                }
+                            };
+                        }
+                    }
+                }
+                // NormalizerSpec
+                (3, Field::Bytes(spec)) => {
+                    for field in ProtoReader::new(spec) {
+                        match field? {
+                            (3, Field::Varint(value)) => model.add_dummy_prefix = value != 0,
+                            (4, Field::Varint(value)) => {
+                                model.remove_extra_whitespaces = value != 0
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(model)
+    }
+
+    fn parse_piece(bytes: &[u8]) -> Result<Piece> {
+        let mut piece = Piece {
+            piece: String::new(),
+            score: 0.0,
+            kind: PieceKind::Normal,
+        };
+        for field in ProtoReader::new(bytes) {
+            match field? {
+                (1, Field::Bytes(text)) => {
+                    piece.piece =
+                        String::from_utf8(text.to_vec()).map_err(|e| invalid_data(e.to_string()))?
+                }
+                (2, Field::Fixed32(score)) => piece.score = f32::from_bits(score),
+                (3, Field::Varint(kind)) => piece.kind = PieceKind::from_proto(kind)?,
+                _ => {}
+            }
+        }
+        Ok(piece)
+    }
+}
+
+/// A character of the normalized text, with the byte range of the text it comes from
+#[derive(Clone, Copy)]
+struct NormalizedChar {
+    c: char,
+    start: usize,
+    end: usize,
+}
+
+/// Splits the normalized text before every `▁`
+fn split_words(chars: &[NormalizedChar]) -> Vec<&[NormalizedChar]> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    for (i, c) in chars.iter().enumerate().skip(1) {
+        if c.c == SPACE {
+            words.push(&chars[start..i]);
+            start = i;
+        }
+    }
+    if start < chars.len() {
+        words.push(&chars[start..]);
+    }
+    words
+}
+
+/// A SentencePiece tokenizer, as used by mBART and other multilingual BART-family models,
+/// loaded from `sentencepiece.bpe.model`. Both unigram and BPE models are supported.
+///
+/// The ids follow the layout of fairseq's dictionaries that these checkpoints were trained with:
+/// `<s>`, `<pad>`, `</s>` and `<unk>` come first, followed by the model's pieces, the language
+/// codes and `<mask>`. Only whitespace is normalized, the model's NFKC rules aren't applied.
+#[derive(Clone)]
+pub struct SentencePieceTokenizer {
+    /// Every piece, indexed by its token id
+    pieces: Vec<Piece>,
+    token_ids: HashMap<String, u32>,
+    model_type: ModelType,
+    /// The length of the longest piece in characters, which bounds the pieces a word is split into
+    max_piece_chars: usize,
+    /// The score of an unknown character, which is lower than that of any piece
+    unk_score: f32,
+    /// Whether a space is added before the text, so its first word is split like the others
+    add_dummy_prefix: bool,
+    /// Whether leading, trailing and repeated whitespace is removed
+    remove_extra_whitespaces: bool,
+    special_tokens: SpecialTokens,
+    added_vocab: AddedVocab,
+}
+
+impl SentencePieceTokenizer {
+    /// Loads a SentencePiece model, adding `lang_codes` before `<mask>` for multilingual models
+    pub fn new<T: AsRef<Path>>(model_path: T, lang_codes: &[&str]) -> Result<Self> {
+        let bytes = fs::read(model_path)?;
+        Self::from_model_proto(ModelProto::parse(&bytes)?, lang_codes)
+    }
+
+    fn from_model_proto(model: ModelProto, lang_codes: &[&str]) -> Result<Self> {
+        if model.pieces.len() < 3 {
+            return Err(invalid_data("the model is missing <unk>, <s> and </s>"));
+        }
+        // fairseq replaces the model's `<unk>`, `<s>` and `</s>` with its own special tokens,
+        // which shifts the other pieces by one
+        let mut pieces = vec![
+            Piece::control("<s>"),
+            Piece::control("<pad>"),
+            Piece::control("</s>"),
+            Piece {
+                kind: PieceKind::Unknown,
+                ..Piece::control("<unk>")
+            },
+        ];
+        pieces.extend(model.pieces.into_iter().skip(3));
+        pieces.extend(lang_codes.iter().map(|code| Piece::control(code)));
+        pieces.push(Piece::control("<mask>"));
+
+        let token_ids = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.piece.clone(), id as u32))
+            .collect();
+        let text_pieces = pieces.iter().filter(|piece| piece.kind.is_text());
+        let max_piece_chars = text_pieces
+            .clone()
+            .map(|piece| piece.piece.chars().count())
+            .max()
+            .unwrap_or(1);
+        let min_score = text_pieces.map(|piece| piece.score).fold(0.0, f32::min);
+        debug!("Loaded SentencePiece model of {} pieces", pieces.len());
+
+        let mut tokenizer = Self {
+            pieces,
+            token_ids,
+            model_type: model.model_type,
+            max_piece_chars,
+            unk_score: min_score - UNK_PENALTY,
+            add_dummy_prefix: model.add_dummy_prefix,
+            remove_extra_whitespaces: model.remove_extra_whitespaces,
+            special_tokens: SpecialTokens::default(),
+            added_vocab: AddedVocab::default(),
+        };
+        let lang_codes = lang_codes
+            .iter()
+            .filter_map(|code| Some((code.to_string(), tokenizer.get_token(code)?)))
+            .collect();
+        tokenizer.special_tokens = SpecialTokens {
+            bos: tokenizer.get_token("<s>"),
+            eos: tokenizer.get_token("</s>"),
+            pad: tokenizer.get_token("<pad>"),
+            unk: tokenizer.get_token("<unk>"),
+            mask: tokenizer.get_token("<mask>"),
+            lang_codes,
+        };
+        Ok(tokenizer)
+    }
+
+    /// Replaces whitespace with `▁`, keeping track of the bytes of `text` every character comes from
+    fn normalize(&self, text: &str) -> Vec<NormalizedChar> {
+        let mut chars: Vec<NormalizedChar> = Vec::with_capacity(text.len() + 1);
+        for (start, c) in text.char_indices() {
+            let end = start + c.len_utf8();
+            if c.is_whitespace() {
+                let after_space = chars.last().is_none_or(|last| last.c == SPACE);
+                if !(self.remove_extra_whitespaces && after_space) {
+                    chars.push(NormalizedChar {
+                        c: SPACE,
+                        start,
+                        end,
+                    });
+                }
+            } else {
+                chars.push(NormalizedChar { c, start, end });
+            }
+        }
+        if self.remove_extra_whitespaces && chars.last().is_some_and(|last| last.c == SPACE) {
+            chars.pop();
+        }
+        if self.add_dummy_prefix {
+            if let Some(first) = chars.first() {
+                let start = first.start;
+                chars.insert(
+                    0,
+                    NormalizedChar {
+                        c: SPACE,
+                        start,
+                        end: start,
+                    },
+                );
+            }
+        }
+        chars
+    }
+
+    /// The id of a piece that text can be split into
+    fn text_piece(&self, piece: &str) -> Option<u32> {
+        let id = *self.token_ids.get(piece)?;
+        self.pieces[id as usize].kind.is_text().then_some(id)
+    }
+
+    /// Splits a word into the pieces with the highest total score with the Viterbi algorithm.
+    /// Returns the id of every piece, or `None` for unknown characters, and the characters it spans.
+    fn unigram(&self, word: &[char]) -> Vec<(Option<u32>, usize, usize)> {
+        // The best score of the characters up to every position, and the piece that ends there
+        let mut best: Vec<Option<(f32, usize, Option<u32>)>> = vec![None; word.len() + 1];
+        best[0] = Some((0.0, 0, None));
+        for start in 0..word.len() {
+            let Some((score, _, _)) = best[start] else {
+                continue;
+            };
+            let mut piece = String::new();
+            let mut has_single_char = false;
+            for end in start + 1..=word.len().min(start + self.max_piece_chars) {
+                piece.push(word[end - 1]);
+                let Some(id) = self.text_piece(&piece) else {
+                    continue;
+                };
+                has_single_char |= end == start + 1;
+                let score = score + self.pieces[id as usize].score;
+                if best[end].is_none_or(|(best_score, _, _)| score > best_score) {
+                    best[end] = Some((score, start, Some(id)));
+                }
+            }
+            if !has_single_char {
+                let score = score + self.unk_score;
+                if best[start + 1].is_none_or(|(best_score, _, _)| score > best_score) {
+                    best[start + 1] = Some((score, start, None));
+                }
+            }
+        }
+
+        let mut pieces = Vec::new();
+        let mut end = word.len();
+        while end > 0 {
+            let (_, start, id) = best[end].expect("every character can be reached as <unk>");
+            pieces.push((id, start, end));
+            end = start;
+        }
+        pieces.reverse();
+        pieces
+    }
+
+    /// Starts from single characters and merges the adjacent pair whose concatenation
+    /// has the highest score until no concatenation is in the vocab
+    fn bpe(&self, word: &[char]) -> Vec<(Option<u32>, usize, usize)> {
+        let mut symbols: Vec<(String, usize, usize)> = word
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.to_string(), i, i + 1))
+            .collect();
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    let id = self.text_piece(&format!("{}{}", pair[0].0, pair[1].0))?;
+                    Some((self.pieces[id as usize].score, i))
+                })
+                .max_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)));
+            let Some((_, i)) = best else {
+                break;
+            };
+            let (right, _, end) = symbols.remove(i + 1);
+            symbols[i].0.push_str(&right);
+            symbols[i].2 = end;
+        }
+        symbols
+            .into_iter()
+            .map(|(symbol, start, end)| (self.text_piece(&symbol), start, end))
+            .collect()
+    }
+}
+
+impl Tokenizer for SentencePieceTokenizer {
+    fn get_piece(&self, token: Token) -> Option<&str> {
+        self.pieces
+            .get(token.id as usize)
+            .map(|piece| piece.piece.as_str())
+    }
+
+    fn get_token(&self, piece: &str) -> Option<Token> {
+        self.token_ids.get(piece).map(|id| Token { id: *id })
+    }
+
+    fn get_special_tokens(&self) -> &SpecialTokens {
+        &self.special_tokens
+    }
+
+    fn is_special(&self, token: Token) -> bool {
+        self.pieces
+            .get(token.id as usize)
+            .is_some_and(|piece| matches!(piece.kind, PieceKind::Control | PieceKind::Unknown))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    fn add_tokens(&mut self, tokens: &[AddedToken]) -> usize {
+        let mut new_tokens = 0;
+        for added_token in tokens.iter().filter(|token| !token.content.is_empty()) {
+            let kind = if added_token.special {
+                PieceKind::Control
+            } else {
+                PieceKind::UserDefined
+            };
+            let id = match self.token_ids.get(&added_token.content) {
+                Some(id) => *id,
+                None => {
+                    let id = self.pieces.len() as u32;
+                    self.pieces.push(Piece {
+                        kind,
+                        ..Piece::control(&added_token.content)
+                    });
+                    self.token_ids.insert(added_token.content.clone(), id);
+                    new_tokens += 1;
+                    id
+                }
+            };
+            self.pieces[id as usize].kind = kind;
+            self.added_vocab.push(added_token.clone(), Token { id });
+        }
+        debug!("Added {new_tokens} new tokens to the vocab");
+        new_tokens
+    }
+
+    /// Turns tokens back into text by joining their pieces and turning `▁` back into spaces
+    fn decode(
+        &self,
+        tokens: &[Token],
+        skip_special_tokens: bool,
+        clean_up_tokenization_spaces: bool,
+    ) -> Box<str> {
+        let mut text = String::new();
+        for token in tokens {
+            let Some(piece) = self.get_piece(*token) else {
+                warn!("Token {} is missing from the vocab, skipping it", token.id);
+                continue;
+            };
+            if skip_special_tokens && self.is_special(*token) {
+                continue;
+            }
+            text.push_str(piece);
+        }
+        let text = text.replace(SPACE, " ");
+        let text = match text.strip_prefix(' ') {
+            Some(text) if self.add_dummy_prefix => text,
+            _ => &text,
+        };
+        if clean_up_tokenization_spaces {
+            clean_up_tokenization(text).into()
+        } else {
+            text.into()
+        }
+    }
+
+    /// Pieces missing from the vocab become `<unk>`
+    fn tokenize_with_offsets(&self, text: &str) -> Vec<(Token, TokenOffset)> {
+        self.added_vocab
+            .tokenize_around(text, |text| self.tokenize_pieces(text))
+    }
+}
+
+impl SentencePieceTokenizer {
+    /// Splits text that holds no added tokens. Every word, which starts at a `▁`,
+    /// is split on its own. Runs of unknown characters become a single `<unk>`.
+    fn tokenize_pieces(&self, text: &str) -> Vec<(Token, TokenOffset)> {
+        let chars = self.normalize(text);
+        let unk = self.special_tokens.unk.expect("<unk> is always added");
+        let mut tokens: Vec<(Token, TokenOffset)> = Vec::new();
+        let mut previous_unk = false;
+        for word in split_words(&chars) {
+            let word_chars: Vec<char> = word.iter().map(|c| c.c).collect();
+            let pieces = match self.model_type {
+                ModelType::Unigram => self.unigram(&word_chars),
+                ModelType::Bpe => self.bpe(&word_chars),
+            };
+            for (id, start, end) in pieces {
+                let trimmed_start = if word_chars[start] == SPACE && end - start > 1 {
+                    start + 1
+                } else {
+                    start
+                };
+                let offset = TokenOffset {
+                    start: word[trimmed_start].start,
+                    end: word[end - 1].end,
+                    special: false,
+                };
+                match id {
+                    Some(id) => {
+                        tokens.push((Token { id }, offset));
+                        previous_unk = false;
+                    }
+                    None if previous_unk => {
+                        let (_, last) = tokens.last_mut().expect("an <unk> was pushed");
+                        last.end = offset.end;
+                    }
+                    None => {
+                        tokens.push((unk, offset));
+                        previous_unk = true;
+                    }
+                }
+            }
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a length-delimited protobuf field
+    fn proto_bytes(field: u8, bytes: &[u8]) -> Vec<u8> {
+        assert!(bytes.len() < 0x80);
+        [&[(field << 3) | 2, bytes.len() as u8], bytes].concat()
+    }
+
+    fn proto_model(model_type: u8, pieces: &[(&str, f32, u8)]) -> Vec<u8> {
+        let mut model = Vec::new();
+        for (piece, score, kind) in pieces {
+            let mut bytes = proto_bytes(1, piece.as_bytes());
+            bytes.push((2 << 3) | 5);
+            bytes.extend_from_slice(&score.to_le_bytes());
+            bytes.extend_from_slice(&[3 << 3, *kind]);
+            model.extend(proto_bytes(1, &bytes));
+        }
+        model.extend(proto_bytes(2, &[3 << 3, model_type]));
+        model
+    }
+
+    #[test]
+    fn splits_like_sentencepiece() {
+        let pieces = [
+            ("<unk>", 0.0, 2),
+            ("<s>", 0.0, 3),
+            ("</s>", 0.0, 3),
+            ("▁", -2.0, 1),
+            ("▁ab", -1.0, 1),
+            ("a", -3.0, 1),
+            ("b", -3.0, 1),
+            ("c", -3.0, 1),
+            ("▁a", -2.5, 1),
+        ];
+        for model_type in [1, 2] {
+            let model = ModelProto::parse(&proto_model(model_type, &pieces)).unwrap();
+            let tokenizer = SentencePieceTokenizer::from_model_proto(model, &["en_XX"]).unwrap();
+            // The model's pieces come after fairseq's four special tokens
+            assert_eq!(tokenizer.get_token("▁ab").unwrap().get_id(), 5);
+            assert_eq!(
+                tokenizer.get_special_tokens().lang_codes["en_XX"].get_id(),
+                10
+            );
+            assert_eq!(tokenizer.get_special_tokens().mask.unwrap().get_id(), 11);
+
+            let text = "ab  ca zz";
+            let (tokens, offsets): (Vec<_>, Vec<_>) =
+                tokenizer.tokenize_with_offsets(text).into_iter().unzip();
+            let ids: Vec<u32> = tokens.iter().map(Token::get_id).collect();
+            // "zz" is unknown and becomes a single <unk>
+            assert_eq!(ids, [5, 4, 8, 6, 4, 3]);
+            let offsets: Vec<(usize, usize)> = offsets
+                .iter()
+                .map(|offset| (offset.start, offset.end))
+                .collect();
+            assert_eq!(offsets, [(0, 2), (2, 3), (4, 5), (5, 6), (6, 7), (7, 9)]);
+
+            assert_eq!(&*tokenizer.decode(&tokens[..4], true, false), "ab ca");
+            let bos = tokenizer.get_special_tokens().bos.unwrap();
+            assert_eq!(
+                &*tokenizer.decode(&[bos, tokens[0]], false, false),
+                "<s> ab"
+            );
+        }
+    }
+}
@@ -1,27 +1,63 @@
-use std::{fs::File, path::Path};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
 
-use candle_core::{quantized::{gguf_file, QTensor}, Device};
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Device,
+};
+use safetensors::tensor::{Metadata, TensorInfo};
 
-use crate::bart_tensor_type::TensorName;
+use crate::{
+    bart_tensor_type::TensorName,
+    error::{BartError, Result},
+};
 
-pub struct Tensor {
-    name: TensorName,
-    tensor: QTensor,
+/// The file name of an unsharded HuggingFace checkpoint
+const SAFETENSORS_FILE: &str = "model.safetensors";
+/// The file name of the index listing the shards of a sharded HuggingFace checkpoint
+const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+/// The safetensors format limits headers to 100MB
+const MAX_HEADER_LEN: u64 = 100_000_000;
+
+/// A model file the weights are read from, which lets the layers load the same way
+/// from every checkpoint format
+pub trait WeightSource {
+    /// Reads the tensor called `tensor_name` onto `device`
+    fn get_tensor(&mut self, tensor_name: TensorName, device: &Device) -> Result<QTensor>;
 }
 
-impl Tensor {
-    pub fn get_tensor(&self) -> &QTensor {
-        &self.tensor
+/// Opens the weights at `path`, which is a GGUF file, a safetensors file, a
+/// `model.safetensors.index.json` or a HuggingFace model directory holding one of the last two
+pub fn open_weights<P: AsRef<Path>>(path: &P) -> Result<Box<dyn WeightSource>> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        let index_path = path.join(SAFETENSORS_INDEX_FILE);
+        if index_path.exists() {
+            return Ok(Box::new(SafetensorsTensors::from_index(&index_path)?));
+        }
+        return Ok(Box::new(SafetensorsTensors::new(
+            &path.join(SAFETENSORS_FILE),
+        )?));
+    }
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("gguf") => Ok(Box::new(BartTensors::new(&path)?)),
+        Some("json") => Ok(Box::new(SafetensorsTensors::from_index(&path)?)),
+        _ => Ok(Box::new(SafetensorsTensors::new(&path)?)),
     }
 }
 
+/// The weights of a GGUF model file
 pub struct BartTensors {
     tensors: gguf_file::Content,
     file: File,
 }
 
 impl BartTensors {
-    pub fn new<P: AsRef<Path>>(gguf_path: &P) -> candle_core::Result<Self> {
+    pub fn new<P: AsRef<Path>>(gguf_path: &P) -> Result<Self> {
         let mut file = std::fs::File::open(gguf_path)?;
         let content = candle_core::quantized::gguf_file::Content::read(&mut file)?;
         Ok(Self {
@@ -29,9 +65,282 @@ impl BartTensors {
             file,
         })
     }
-    pub fn get_tensor(&mut self, tensor_name: TensorName, device: &Device) -> QTensor {
-        self.tensors
-            .tensor(&mut self.file, &tensor_name.to_string(), device)
-            .unwrap_or_else(|_| panic!("tensor {tensor_name} not found in BartTensors"))
+}
+
+impl WeightSource for BartTensors {
+    fn get_tensor(&mut self, tensor_name: TensorName, device: &Device) -> Result<QTensor> {
+        let name = tensor_name.to_string();
+        if !self.tensors.tensor_infos.contains_key(&name) {
+            return Err(BartError::MissingTensor(tensor_name));
+        }
+        Ok(self.tensors.tensor(&mut self.file, &name, device)?)
+    }
+}
+
+fn invalid_checkpoint(message: String) -> BartError {
+    BartError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// The names `tensor_name` may be stored under in a HuggingFace checkpoint.
+/// The token embeddings are shared, so checkpoints keep a single copy under any of their names,
+/// and checkpoints saved from `BartModel` leave out the `model.` prefix.
+fn checkpoint_names(tensor_name: &TensorName) -> Vec<String> {
+    let names = match tensor_name {
+        TensorName::EmbedTokensWeights => vec![
+            tensor_name.to_string(),
+            "model.shared.weight".to_owned(),
+            "model.encoder.embed_tokens.weight".to_owned(),
+        ],
+        _ => vec![tensor_name.to_string()],
+    };
+    names
+        .into_iter()
+        .flat_map(|name| {
+            let unprefixed = name.strip_prefix("model.").map(str::to_owned);
+            std::iter::once(name).chain(unprefixed)
+        })
+        .collect()
+}
+
+/// Fails unless the data of the tensor `name` lies within the `data_len` bytes
+/// after the header and is exactly as long as its shape and dtype require
+fn check_tensor_info(name: &str, info: &TensorInfo, data_len: u64) -> Result<()> {
+    let (start, end) = info.data_offsets;
+    if start > end || end as u64 > data_len {
+        return Err(invalid_checkpoint(format!(
+            "tensor {name} at bytes {start}..{end} is outside the {data_len} bytes of data"
+        )));
+    }
+    let expected_len = info
+        .shape
+        .iter()
+        .try_fold(info.dtype.size(), |len, dim| len.checked_mul(*dim));
+    if expected_len != Some(end - start) {
+        return Err(invalid_checkpoint(format!(
+            "tensor {name} of shape {:?} and dtype {:?} is stored in {} bytes",
+            info.shape,
+            info.dtype,
+            end - start
+        )));
+    }
+    Ok(())
+}
+
+/// A safetensors file, of which only the header is read up front
+struct SafetensorsFile {
+    file: File,
+    /// The position of the tensor data in the file, right after the header
+    data_start: u64,
+    metadata: Metadata,
+}
+
+impl SafetensorsFile {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header_len = [0; 8];
+        file.read_exact(&mut header_len)?;
+        let header_len = u64::from_le_bytes(header_len);
+        if header_len > MAX_HEADER_LEN {
+            return Err(invalid_checkpoint(format!(
+                "the header of {} is {header_len} bytes long",
+                path.display()
+            )));
+        }
+        let mut header = vec![0; header_len as usize];
+        file.read_exact(&mut header)?;
+        let metadata: Metadata = serde_json::from_slice(&header).map_err(|e| {
+            invalid_checkpoint(format!("invalid header in {}: {e}", path.display()))
+        })?;
+        let data_start = 8 + header_len;
+        // The header was read in full, so the file is at least `data_start` bytes long
+        let data_len = file.metadata()?.len() - data_start;
+        for (name, info) in metadata.tensors() {
+            check_tensor_info(&name, info, data_len)?;
+        }
+        Ok(Self {
+            file,
+            data_start,
+            metadata,
+        })
+    }
+
+    fn read(&mut self, name: &str, device: &Device) -> Result<candle_core::Tensor> {
+        let info = self
+            .metadata
+            .info(name)
+            .ok_or_else(|| invalid_checkpoint(format!("tensor {name} is not in the file")))?;
+        let (start, end) = info.data_offsets;
+        let mut data = vec![0; end - start];
+        self.file
+            .seek(SeekFrom::Start(self.data_start + start as u64))?;
+        self.file.read_exact(&mut data)?;
+        Ok(candle_core::Tensor::from_raw_buffer(
+            &data,
+            DType::try_from(info.dtype)?,
+            &info.shape,
+            device,
+        )?)
+    }
+}
+
+/// The part of `model.safetensors.index.json` that says which shard holds each tensor
+#[derive(serde::Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// The weights of a HuggingFace safetensors checkpoint, in one file or sharded into several
+pub struct SafetensorsTensors {
+    files: Vec<SafetensorsFile>,
+    /// The index in `files` of the file every tensor is stored in
+    weight_map: HashMap<String, usize>,
+}
+
+impl SafetensorsTensors {
+    /// Opens a single `model.safetensors` file
+    pub fn new<P: AsRef<Path>>(path: &P) -> Result<Self> {
+        Self::from_files(&[path.as_ref()])
+    }
+
+    /// Opens a checkpoint sharded into the files listed in `model.safetensors.index.json`,
+    /// which are looked up next to the index
+    pub fn from_index<P: AsRef<Path>>(index_path: &P) -> Result<Self> {
+        let index_path = index_path.as_ref();
+        let contents = fs::read_to_string(index_path)?;
+        let index: SafetensorsIndex = serde_json::from_str(&contents).map_err(|e| {
+            invalid_checkpoint(format!("invalid index {}: {e}", index_path.display()))
+        })?;
+        let shard_paths: Vec<_> = index
+            .weight_map
+            .values()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|shard| index_path.with_file_name(shard))
+            .collect();
+        Self::from_files(&shard_paths)
+    }
+
+    fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut weight_map = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            let file = SafetensorsFile::open(path.as_ref())?;
+            weight_map.extend(file.metadata.tensors().into_keys().map(|name| (name, i)));
+            files.push(file);
+        }
+        Ok(Self { files, weight_map })
+    }
+}
+
+impl WeightSource for SafetensorsTensors {
+    fn get_tensor(&mut self, tensor_name: TensorName, device: &Device) -> Result<QTensor> {
+        let Some((name, i)) = checkpoint_names(&tensor_name).into_iter().find_map(|name| {
+            let i = *self.weight_map.get(&name)?;
+            Some((name, i))
+        }) else {
+            return Err(BartError::MissingTensor(tensor_name));
+        };
+        let tensor = self.files[i].read(&name, device)?;
+        // Half precision weights stay in half precision, like in a converted f16 GGUF file
+        let dtype = match tensor.dtype() {
+            DType::F16 => GgmlDType::F16,
+            _ => GgmlDType::F32,
+        };
+        Ok(QTensor::quantize(&tensor, dtype)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bart_tensor_type::{FcLayer, FcType, Stack, TensorType};
+
+    #[test]
+    fn loads_sharded_safetensors() {
+        let device = Device::Cpu;
+        let dir =
+            std::env::temp_dir().join(format!("bart-rs-sharded-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let shared = candle_core::Tensor::arange(0f32, 6., &device)
+            .unwrap()
+            .reshape((3, 2))
+            .unwrap();
+        let fc1_bias = candle_core::Tensor::new(&[1f32, 2.], &device)
+            .unwrap()
+            .to_dtype(DType::F16)
+            .unwrap();
+        let shards = [
+            (
+                "model-00001-of-00002.safetensors",
+                "model.shared.weight",
+                &shared,
+            ),
+            (
+                "model-00002-of-00002.safetensors",
+                "model.encoder.layers.0.fc1.bias",
+                &fc1_bias,
+            ),
+        ];
+        for (file, name, tensor) in shards {
+            candle_core::safetensors::save(
+                &HashMap::from([(name, tensor.clone())]),
+                dir.join(file),
+            )
+            .unwrap();
+        }
+        let weight_map: HashMap<_, _> = shards.iter().map(|(file, name, _)| (name, file)).collect();
+        let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+        fs::write(dir.join(SAFETENSORS_INDEX_FILE), index.to_string()).unwrap();
+
+        let mut tensors = open_weights(&dir).unwrap();
+        let embed_tokens = tensors
+            .get_tensor(TensorName::EmbedTokensWeights, &device)
+            .unwrap();
+        assert_eq!(
+            embed_tokens
+                .dequantize(&device)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap(),
+            shared.to_vec2::<f32>().unwrap()
+        );
+        let fc1_bias = tensors
+            .get_tensor(
+                TensorName::Fc(FcLayer {
+                    stack: Stack::Encoder,
+                    fc_type: FcType::Fc1,
+                    tensor_type: TensorType::Bias,
+                    layer: 0,
+                }),
+                &device,
+            )
+            .unwrap();
+        assert_eq!(fc1_bias.dtype(), GgmlDType::F16);
+        assert!(matches!(
+            tensors.get_tensor(TensorName::FinalLogitsBias, &device),
+            Err(BartError::MissingTensor(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_safetensors() {
+        let device = Device::Cpu;
+        let path = std::env::temp_dir().join(format!(
+            "bart-rs-truncated-{}.safetensors",
+            std::process::id()
+        ));
+        let shared = candle_core::Tensor::arange(0f32, 6., &device).unwrap();
+        candle_core::safetensors::save(&HashMap::from([("model.shared.weight", shared)]), &path)
+            .unwrap();
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 4]).unwrap();
+
+        let error = SafetensorsTensors::new(&path).err();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            matches!(&error, Some(BartError::Io(e)) if e.kind() == io::ErrorKind::InvalidData),
+            "{error:?}"
+        );
     }
 }
@@ -1,26 +1,353 @@
 #![allow(dead_code)]
 
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs,
+    ops::Range,
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+};
 
-use tracing::debug;
+use fancy_regex::Regex;
+use rayon::prelude::*;
+use tracing::{debug, warn};
 
-#[derive(Clone, Copy)]
-pub struct Token {
+use crate::error::{BartError, Result};
+use crate::sentencepiece::SentencePieceTokenizer;
+
+/// The file a HuggingFace tokenizer is saved to, holding the vocab, merges and added tokens
+const TOKENIZER_FILE: &str = "tokenizer.json";
+/// The SentencePiece model of mBART and other multilingual checkpoints
+const SENTENCEPIECE_FILE: &str = "sentencepiece.bpe.model";
+/// The vocab of checkpoints saved without `tokenizer.json`, with `merges.txt` next to it
+const VOCAB_FILE: &str = "vocab.json";
+/// Lists the language codes of multilingual checkpoints as `additional_special_tokens`
+const SPECIAL_TOKENS_MAP_FILE: &str = "special_tokens_map.json";
+
+/// The pattern GPT-2 splits text with before applying BPE: contractions, runs of letters,
+/// runs of numbers and runs of other symbols, each with an optional leading space, and whitespace
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+fn gpt2_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(GPT2_PATTERN).expect("the GPT-2 pattern is valid"))
+}
+
+/// GPT-2 runs BPE on bytes, but stores its vocab as text. Every byte is mapped to a
+/// printable character: printable bytes map to themselves and the others are shifted
+/// past 255, which is why a space becomes `Ġ` and a newline becomes `Ċ`.
+pub fn bytes_to_unicode() -> &'static [char; 256] {
+    static TABLE: OnceLock<[char; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = ['\0'; 256];
+        let mut shifted = 0;
+        for byte in 0..=255u8 {
+            table[byte as usize] = if matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) {
+                char::from(byte)
+            } else {
+                shifted += 1;
+                char::from_u32(255 + shifted).expect("shifted bytes stay below 0x200")
+            };
+        }
+        table
+    })
+}
+
+/// The reverse of [`bytes_to_unicode`]
+fn unicode_to_bytes() -> &'static HashMap<char, u8> {
+    static TABLE: OnceLock<HashMap<char, u8>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        bytes_to_unicode()
+            .iter()
+            .enumerate()
+            .map(|(byte, c)| (*c, byte as u8))
+            .collect()
+    })
+}
+
+/// The tokens BART uses to structure its input rather than to represent text
+const SPECIAL_TOKENS: [&str; 5] = ["<s>", "</s>", "<pad>", "<unk>", "<mask>"];
+
+/// Removes the spaces the tokenizer puts before punctuation and contractions,
+/// like HF's `clean_up_tokenization`
+pub(crate) fn clean_up_tokenization(text: &str) -> String {
+    [
+        (" .", "."),
+        (" ?", "?"),
+        (" !", "!"),
+        (" ,", ","),
+        (" ' ", "'"),
+        (" n't", "n't"),
+        (" 'm", "'m"),
+        (" 's", "'s"),
+        (" 've", "'ve"),
+        (" 're", "'re"),
+    ]
+    .iter()
+    .fold(text.to_owned(), |text, (from, to)| text.replace(from, to))
+}
+
+/// Parses a merge rule, written as the two pieces separated by a space
+fn parse_merge(merge: &str) -> Result<(String, String)> {
+    merge
+        .split_once(' ')
+        .map(|(left, right)| (left.to_owned(), right.to_owned()))
+        .ok_or_else(|| BartError::Tokenizer(format!("invalid merge {merge:?}")))
+}
+
+/// The parts of a HuggingFace `tokenizer.json` that the tokenizer is built from
+#[derive(serde::Deserialize)]
+struct TokenizerJson {
+    #[serde(default)]
+    added_tokens: Vec<AddedTokenJson>,
+    pre_tokenizer: Option<ComponentJson>,
+    post_processor: Option<ComponentJson>,
+    model: BpeModelJson,
+}
+
+/// A `pre_tokenizer` or `post_processor` section of `tokenizer.json`
+#[derive(serde::Deserialize)]
+struct ComponentJson {
+    #[serde(rename = "type")]
+    component_type: String,
+    #[serde(default)]
+    add_prefix_space: bool,
+}
+
+impl ComponentJson {
+    /// Fails unless the `section` is missing or is the `expected` component without a prefix space,
+    /// since the text is always split and formatted the way BART's tokenizer does it
+    fn check(component: Option<&Self>, section: &str, expected: &str) -> Result<()> {
+        match component {
+            Some(component) if component.component_type != expected => {
+                Err(BartError::Tokenizer(format!(
+                    "expected a {expected} {section} but found {}",
+                    component.component_type
+                )))
+            }
+            Some(component) if component.add_prefix_space => Err(BartError::Tokenizer(format!(
+                "the {section} adds a prefix space, which isn't supported"
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct AddedTokenJson {
     id: u32,
+    #[serde(flatten)]
+    token: AddedToken,
+}
+
+#[derive(serde::Deserialize)]
+struct BpeModelJson {
+    #[serde(rename = "type")]
+    model_type: Option<String>,
+    vocab: HashMap<String, u32>,
+    merges: Vec<MergeJson>,
+    unk_token: Option<String>,
+}
+
+/// Older files write merges as `"a b"` and newer ones as `["a", "b"]`
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum MergeJson {
+    Joined(String),
+    Pair(String, String),
+}
+
+/// Whether the piece is a language code such as `en_XX`, as used by multilingual models like mBART
+fn is_lang_code(piece: &str) -> bool {
+    matches!(
+        piece.as_bytes(),
+        [a, b, b'_', c, d] if a.is_ascii_lowercase()
+            && b.is_ascii_lowercase()
+            && c.is_ascii_uppercase()
+            && d.is_ascii_uppercase()
+    )
+}
+
+/// A token added on top of the vocab that's never split, like HF's `AddedToken`.
+/// Added tokens are matched in the text before it's split any further.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct AddedToken {
+    pub content: String,
+    /// Whether the token only matches on its own, never inside a word
+    #[serde(default)]
+    pub single_word: bool,
+    /// Whether the whitespace before the token is taken along with it
+    #[serde(default)]
+    pub lstrip: bool,
+    /// Whether the whitespace after the token is taken along with it
+    #[serde(default)]
+    pub rstrip: bool,
+    /// Whether the token structures the input, so that decoding can skip it
+    #[serde(default)]
+    pub special: bool,
+}
+
+impl AddedToken {
+    pub fn new(content: &str) -> Self {
+        Self {
+            content: content.to_owned(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Whether the range of `text` isn't part of a longer word
+fn is_single_word(text: &str, range: Range<usize>) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    !text[..range.start]
+        .chars()
+        .next_back()
+        .is_some_and(is_word_char)
+        && !text[range.end..].chars().next().is_some_and(is_word_char)
+}
+
+/// An added token together with the token it stands for
+type AddedEntry = (AddedToken, Token);
+
+/// The tokens added on top of a tokenizer's vocab, along with the token each one stands for
+#[derive(Clone, Default, Debug)]
+pub struct AddedVocab {
+    tokens: Vec<AddedEntry>,
+}
+
+impl AddedVocab {
+    /// Adds a token, replacing the options of a token with the same content
+    pub(crate) fn push(&mut self, added_token: AddedToken, token: Token) {
+        self.tokens
+            .retain(|(existing, _)| existing.content != added_token.content);
+        self.tokens.push((added_token, token));
+    }
+
+    pub fn get_tokens(&self) -> &[AddedEntry] {
+        &self.tokens
+    }
+
+    /// Finds the first added token at or after `from`, preferring the longest one when several
+    /// start at the same place. Returns the token, the range it matched and the range it takes
+    /// up together with the whitespace stripped along with it.
+    fn find(&self, text: &str, from: usize) -> Option<(&AddedEntry, Range<usize>, Range<usize>)> {
+        let (added, matched) = self
+            .tokens
+            .iter()
+            .filter_map(|added| {
+                let content = added.0.content.as_str();
+                let start = text[from..]
+                    .match_indices(content)
+                    .map(|(start, _)| from + start)
+                    .find(|start| {
+                        !added.0.single_word || is_single_word(text, *start..start + content.len())
+                    })?;
+                Some((added, start..start + content.len()))
+            })
+            .min_by_key(|(_, matched)| (matched.start, Reverse(matched.end)))?;
+
+        let mut stripped = matched.clone();
+        if added.0.lstrip {
+            stripped.start = from + text[from..matched.start].trim_end().len();
+        }
+        if added.0.rstrip {
+            stripped.end = text.len() - text[matched.end..].trim_start().len();
+        }
+        Some((added, matched, stripped))
+    }
+
+    /// Splits the text into the ranges matched by added tokens and the ranges between them,
+    /// leaving out the whitespace stripped along with added tokens
+    fn split(&self, text: &str) -> Vec<(Range<usize>, Option<&AddedEntry>)> {
+        let mut ranges = Vec::new();
+        let mut from = 0;
+        while let Some((added, matched, stripped)) = self.find(text, from) {
+            if stripped.start > from {
+                ranges.push((from..stripped.start, None));
+            }
+            ranges.push((matched, Some(added)));
+            from = stripped.end;
+        }
+        if from < text.len() {
+            ranges.push((from..text.len(), None));
+        }
+        ranges
+    }
+
+    /// Tokenizes the text between the added tokens with `tokenize`,
+    /// moving the offsets it returns to the matching ranges of the whole text
+    pub(crate) fn tokenize_around(
+        &self,
+        text: &str,
+        tokenize: impl Fn(&str) -> Vec<(Token, TokenOffset)>,
+    ) -> Vec<(Token, TokenOffset)> {
+        let mut tokens = Vec::new();
+        for (range, added) in self.split(text) {
+            match added {
+                Some((added_token, token)) => tokens.push((
+                    *token,
+                    TokenOffset {
+                        start: range.start,
+                        end: range.end,
+                        special: added_token.special,
+                    },
+                )),
+                None => tokens.extend(tokenize(&text[range.clone()]).into_iter().map(
+                    |(token, offset)| {
+                        let offset = TokenOffset {
+                            start: range.start + offset.start,
+                            end: range.start + offset.end,
+                            ..offset
+                        };
+                        (token, offset)
+                    },
+                )),
+            }
+        }
+        tokens
+    }
+}
+
+/// The byte range of the original text a token was produced from, like HF's offset mapping.
+/// Ranges may end inside a multi-byte character, since BPE works on bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TokenOffset {
+    pub start: usize,
+    pub end: usize,
+    /// Whether this is a special token. Those added around the text have the empty range `0..0`.
+    pub special: bool,
+}
+
+impl TokenOffset {
+    /// The offset of a special token that doesn't come from the text
+    pub fn special() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            special: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Token {
+    pub(crate) id: u32,
 }
 
 impl Token {
-    pub fn from_substr(tokenizer: &WordPieceTokenizer, substr: &str) -> Option<Self> {
-        let id: u32 = *tokenizer.vocab.iter().find(|x| x.1 == substr)?.0;
-        Some(Self { id })
+    pub fn from_substr(tokenizer: &dyn Tokenizer, substr: &str) -> Option<Self> {
+        tokenizer.get_token(substr)
     }
 
-    pub fn new(tokenizer: &WordPieceTokenizer, id: u32) -> Option<Self> {
-        tokenizer.vocab.get(&id)?;
-        Some(Self { id })
+    pub fn new(tokenizer: &dyn Tokenizer, id: u32) -> Option<Self> {
+        let token = Self { id };
+        tokenizer.get_piece(token)?;
+        Some(token)
     }
-    pub fn to_substr(self, tokenizer: &WordPieceTokenizer) -> Option<Box<str>> {
-        tokenizer.vocab.get(&self.id).map(|x| {
+    pub fn to_substr(self, tokenizer: &dyn Tokenizer) -> Option<Box<str>> {
+        tokenizer.get_piece(self).map(|x| {
             x.to_owned()
                 .into_boxed_str()
                 .replace('Ġ', "_")
@@ -32,24 +359,882 @@ impl Token {
     }
 }
 
+/// The special tokens of a tokenizer, looked up once when the tokenizer is created.
+/// Tokens missing from the vocab are `None`.
+#[derive(Clone, Default, Debug)]
+pub struct SpecialTokens {
+    /// `<s>`, which starts every sequence
+    pub bos: Option<Token>,
+    /// `</s>`, which ends every sequence
+    pub eos: Option<Token>,
+    pub pad: Option<Token>,
+    pub unk: Option<Token>,
+    pub mask: Option<Token>,
+    /// The language codes of multilingual models, such as `en_XX`
+    pub lang_codes: HashMap<String, Token>,
+}
+
+/// What the `InputSeq` pipeline and generation need from a tokenizer, so that
+/// BART-family models that don't use byte-level BPE can run through them too.
+/// Tokenizers are shared between threads to tokenize batches in parallel.
+pub trait Tokenizer: Send + Sync {
+    /// The piece of the vocab a token stands for
+    fn get_piece(&self, token: Token) -> Option<&str>;
+
+    /// Looks up the token of a piece of the vocab
+    fn get_token(&self, piece: &str) -> Option<Token>;
+
+    fn get_special_tokens(&self) -> &SpecialTokens;
+
+    /// Whether the token structures the input rather than represents text
+    fn is_special(&self, token: Token) -> bool;
+
+    /// The number of tokens, which the model needs token embeddings for
+    fn vocab_size(&self) -> usize;
+
+    /// Adds tokens that are never split, like HF's `add_tokens`. Tokens already in the vocab
+    /// keep their id and the others are appended to it. Returns the number of new tokens.
+    fn add_tokens(&mut self, tokens: &[AddedToken]) -> usize;
+
+    /// Splits text into tokens, together with the range of `text` every token was
+    /// produced from, like HF's `return_offsets_mapping`. As with HF's `trim_offsets`,
+    /// the ranges leave out the space a word starts with.
+    fn tokenize_with_offsets(&self, text: &str) -> Vec<(Token, TokenOffset)>;
+
+    /// Turns tokens back into text. `skip_special_tokens` drops tokens such as `<s>` and `<pad>`,
+    /// and `clean_up_tokenization_spaces` removes the spaces before punctuation and contractions.
+    fn decode(
+        &self,
+        tokens: &[Token],
+        skip_special_tokens: bool,
+        clean_up_tokenization_spaces: bool,
+    ) -> Box<str>;
+
+    /// Splits text into tokens
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        self.tokenize_with_offsets(text)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    /// Tokenizes every text of the batch on all CPU cores, returning the tokens in input order
+    fn tokenize_batch(&self, texts: &[&str]) -> Vec<Vec<(Token, TokenOffset)>> {
+        texts
+            .par_iter()
+            .map(|text| self.tokenize_with_offsets(text))
+            .collect()
+    }
+
+    /// Decodes every sequence of the batch, in order
+    fn decode_batch(
+        &self,
+        batch: &[&[Token]],
+        skip_special_tokens: bool,
+        clean_up_tokenization_spaces: bool,
+    ) -> Vec<Box<str>> {
+        batch
+            .iter()
+            .map(|tokens| self.decode(tokens, skip_special_tokens, clean_up_tokenization_spaces))
+            .collect()
+    }
+}
+
+/// The part of `special_tokens_map.json` that lists the language codes of multilingual checkpoints
+#[derive(serde::Deserialize)]
+struct SpecialTokensMapJson {
+    #[serde(default)]
+    additional_special_tokens: Vec<String>,
+}
+
+/// Loads the tokenizer of a HuggingFace model directory from `sentencepiece.bpe.model`,
+/// `tokenizer.json` or `vocab.json`, whichever is found first.
+/// SentencePiece comes first because mBART repos also ship a `tokenizer.json` with a
+/// Unigram model, which the byte-level BPE tokenizer can't load.
+pub fn open_tokenizer<P: AsRef<Path>>(dir: &P) -> Result<Box<dyn Tokenizer>> {
+    let dir = dir.as_ref();
+    let sentencepiece_path = dir.join(SENTENCEPIECE_FILE);
+    if sentencepiece_path.exists() {
+        let special_tokens_path = dir.join(SPECIAL_TOKENS_MAP_FILE);
+        let lang_codes = if special_tokens_path.exists() {
+            let contents = fs::read_to_string(special_tokens_path)?;
+            let special_tokens: SpecialTokensMapJson =
+                serde_json::from_str(&contents).map_err(|e| BartError::Tokenizer(e.to_string()))?;
+            special_tokens.additional_special_tokens
+        } else {
+            warn!(
+                "{} has no {SPECIAL_TOKENS_MAP_FILE}, so language codes and <mask> won't get fairseq's ids",
+                dir.display()
+            );
+            Vec::new()
+        };
+        let lang_codes: Vec<&str> = lang_codes.iter().map(String::as_str).collect();
+        return Ok(Box::new(SentencePieceTokenizer::new(
+            &sentencepiece_path,
+            &lang_codes,
+        )?));
+    }
+    let tokenizer_path = dir.join(TOKENIZER_FILE);
+    if tokenizer_path.exists() {
+        return Ok(Box::new(WordPieceTokenizer::from_tokenizer_json(
+            &tokenizer_path,
+        )?));
+    }
+    Ok(Box::new(WordPieceTokenizer::new(dir.join(VOCAB_FILE))?))
+}
+
+/// The most words whose pieces [`BpeCache`] keeps, like HF's cache
+const BPE_CACHE_CAPACITY: usize = 10_000;
+
+/// The pieces BPE split every word into, shared by all the threads and clones of a tokenizer.
+/// Words repeat a lot in natural text, so most of them only need to be merged once.
+#[derive(Clone, Default)]
+struct BpeCache(Arc<RwLock<HashMap<String, Vec<String>>>>);
+
+impl BpeCache {
+    fn get(&self, word: &str) -> Option<Vec<String>> {
+        self.0.read().ok()?.get(word).cloned()
+    }
+
+    fn insert(&self, word: &str, pieces: &[String]) {
+        if let Ok(mut cache) = self.0.write() {
+            if cache.len() < BPE_CACHE_CAPACITY {
+                cache.insert(word.to_owned(), pieces.to_vec());
+            }
+        }
+    }
+}
+
+/// GPT-2's byte-level BPE tokenizer, as used by BART and RoBERTa.
+/// Words are split into characters that are merged back together following
+/// the merge rules of `merges.txt`, highest priority first.
 #[derive(serde::Deserialize, Clone, Default)]
 pub struct WordPieceTokenizer {
     vocab: HashMap<u32, String>,
+    /// One more than the highest id of the vocab, which the next added token gets
+    #[serde(skip)]
+    next_id: u32,
+    /// The vocab in the other direction, used to look up the pieces produced by BPE
+    token_ids: HashMap<String, u32>,
+    /// The priority of every merge, where lower ranks are merged first
+    merge_ranks: HashMap<(String, String), usize>,
+    /// The ids of the tokens that structure the input rather than represent text
+    special_ids: HashSet<u32>,
+    #[serde(skip)]
+    special_tokens: SpecialTokens,
+    #[serde(skip)]
+    added_vocab: AddedVocab,
+    #[serde(skip)]
+    bpe_cache: BpeCache,
 }
 
 impl WordPieceTokenizer {
-    pub fn new<T: AsRef<Path>>(vocab_path: T) -> Result<Self, std::io::Error> {
+    /// Loads `vocab.json` and the `merges.txt` next to it
+    pub fn new<T: AsRef<Path>>(vocab_path: T) -> Result<Self> {
+        let vocab_path = vocab_path.as_ref();
         let contents = fs::read_to_string(vocab_path)?;
-        let vocab: HashMap<String, u32> = serde_json::from_str(&contents)?;
-        let vocab: HashMap<u32, String> = vocab
+        let vocab: HashMap<String, u32> =
+            serde_json::from_str(&contents).map_err(|e| BartError::Tokenizer(e.to_string()))?;
+
+        let merges_path = vocab_path.with_file_name("merges.txt");
+        let contents = fs::read_to_string(merges_path)?;
+        let merges = contents
+            .lines()
+            .filter(|line| !line.starts_with("#version") && !line.is_empty())
+            .map(parse_merge)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::from_parts(vocab, merges))
+    }
+
+    /// Loads a HuggingFace `tokenizer.json`, which holds the vocab, the merges
+    /// and the added tokens in a single file
+    pub fn from_tokenizer_json<T: AsRef<Path>>(path: T) -> Result<Self> {
+        Self::from_tokenizer_json_str(&fs::read_to_string(path)?)
+    }
+
+    /// Like [`WordPieceTokenizer::from_tokenizer_json`], but from the contents of the file
+    pub fn from_tokenizer_json_str(contents: &str) -> Result<Self> {
+        let config =
+            serde_json::from_str(contents).map_err(|e| BartError::Tokenizer(e.to_string()))?;
+        Self::from_tokenizer_config(config)
+    }
+
+    fn from_tokenizer_config(config: TokenizerJson) -> Result<Self> {
+        let BpeModelJson {
+            model_type,
+            mut vocab,
+            merges,
+            unk_token,
+        } = config.model;
+        if let Some(model_type) = model_type.filter(|model_type| model_type != "BPE") {
+            return Err(BartError::Tokenizer(format!(
+                "expected a BPE model but found {model_type}"
+            )));
+        }
+        ComponentJson::check(config.pre_tokenizer.as_ref(), "pre_tokenizer", "ByteLevel")?;
+        ComponentJson::check(
+            config.post_processor.as_ref(),
+            "post_processor",
+            "RobertaProcessing",
+        )?;
+        let merges = merges
+            .into_iter()
+            .map(|merge| match merge {
+                MergeJson::Joined(merge) => parse_merge(&merge),
+                MergeJson::Pair(left, right) => Ok((left, right)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Added tokens aren't necessarily part of the model's vocab
+        for added_token in &config.added_tokens {
+            vocab.insert(added_token.token.content.clone(), added_token.id);
+        }
+        let mut tokenizer = Self::from_parts(vocab, merges);
+        let added_tokens: Vec<_> = config
+            .added_tokens
+            .into_iter()
+            .map(|added_token| added_token.token)
+            .collect();
+        tokenizer.add_tokens(&added_tokens);
+        tokenizer.special_ids = added_tokens
+            .iter()
+            .filter(|added_token| added_token.special)
+            .filter_map(|added_token| tokenizer.token_ids.get(&added_token.content).copied())
+            .collect();
+        tokenizer.find_special_tokens(unk_token.as_deref().unwrap_or("<unk>"));
+        Ok(tokenizer)
+    }
+
+    /// Builds the tokenizer from a token to id map and the merges in order of priority
+    fn from_parts(token_ids: HashMap<String, u32>, merges: Vec<(String, String)>) -> Self {
+        let vocab = token_ids
             .iter()
             .map(|(token, id)| (*id, token.to_owned()))
             .collect();
-        debug!("Loaded vocabulary of {} tokens", vocab.len());
-        Ok(Self { vocab })
+        let next_id = token_ids.values().max().map_or(0, |id| id + 1);
+        let merge_ranks = merges
+            .into_iter()
+            .enumerate()
+            .map(|(rank, merge)| (merge, rank))
+            .collect();
+        let special_ids = SPECIAL_TOKENS
+            .iter()
+            .filter_map(|token| token_ids.get(*token).copied())
+            .collect();
+        debug!("Loaded vocabulary of {} tokens", token_ids.len());
+        let mut tokenizer = Self {
+            vocab,
+            next_id,
+            token_ids,
+            merge_ranks,
+            special_ids,
+            special_tokens: SpecialTokens::default(),
+            added_vocab: AddedVocab::default(),
+            bpe_cache: BpeCache::default(),
+        };
+        // Like BART's tokenizer, the special tokens are never split and
+        // `<mask>` takes the space before it along
+        let special_tokens: Vec<_> = SPECIAL_TOKENS
+            .iter()
+            .filter(|content| tokenizer.token_ids.contains_key(**content))
+            .map(|content| AddedToken {
+                lstrip: *content == "<mask>",
+                special: true,
+                ..AddedToken::new(content)
+            })
+            .collect();
+        tokenizer.add_tokens(&special_tokens);
+        tokenizer.find_special_tokens("<unk>");
+        tokenizer
+    }
+
+    /// Saves the vocab, the merges and the added tokens as a HuggingFace `tokenizer.json`,
+    /// which [`WordPieceTokenizer::from_tokenizer_json`] loads back
+    pub fn save_tokenizer_json<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        Ok(fs::write(path, self.to_tokenizer_json()?)?)
+    }
+
+    /// The contents of the `tokenizer.json` that [`WordPieceTokenizer::save_tokenizer_json`] writes
+    pub fn to_tokenizer_json(&self) -> Result<String> {
+        let mut merges: Vec<_> = self.merge_ranks.iter().collect();
+        merges.sort_by_key(|(_, rank)| **rank);
+        let merges: Vec<_> = merges
+            .into_iter()
+            .map(|((left, right), _)| [left, right])
+            .collect();
+        let added_tokens: Vec<_> = self
+            .added_vocab
+            .get_tokens()
+            .iter()
+            .map(|(added_token, token)| AddedTokenJson {
+                id: token.id,
+                token: added_token.clone(),
+            })
+            .collect();
+        let unk_token = self.special_tokens.unk.and_then(|unk| self.get_piece(unk));
+        let byte_level = serde_json::json!({
+            "type": "ByteLevel",
+            "add_prefix_space": false,
+            "trim_offsets": true,
+            "use_regex": true
+        });
+        let post_processor = match (self.special_tokens.bos, self.special_tokens.eos) {
+            (Some(bos), Some(eos)) => serde_json::json!({
+                "type": "RobertaProcessing",
+                "sep": [self.get_piece(eos), eos.id],
+                "cls": [self.get_piece(bos), bos.id],
+                "trim_offsets": true,
+                "add_prefix_space": false
+            }),
+            _ => serde_json::Value::Null,
+        };
+        let config = serde_json::json!({
+            "version": "1.0",
+            "added_tokens": added_tokens,
+            "pre_tokenizer": byte_level,
+            "post_processor": post_processor,
+            "decoder": byte_level,
+            "model": {
+                "type": "BPE",
+                "unk_token": unk_token,
+                "vocab": self.token_ids,
+                "merges": merges
+            }
+        });
+        serde_json::to_string_pretty(&config).map_err(|e| BartError::Tokenizer(e.to_string()))
+    }
+
+    /// Fills the special token table from the vocab. Language codes are only taken
+    /// from the tokens already marked as special.
+    fn find_special_tokens(&mut self, unk_token: &str) {
+        let lang_codes = self
+            .special_ids
+            .iter()
+            .filter_map(|id| {
+                let piece = self.vocab.get(id)?;
+                is_lang_code(piece).then(|| (piece.clone(), Token { id: *id }))
+            })
+            .collect();
+        self.special_tokens = SpecialTokens {
+            bos: self.get_token("<s>"),
+            eos: self.get_token("</s>"),
+            pad: self.get_token("<pad>"),
+            unk: self.get_token(unk_token),
+            mask: self.get_token("<mask>"),
+            lang_codes,
+        };
+        debug!("Found special tokens {:?}", self.special_tokens);
     }
 
     pub fn get_vocab(&self) -> &HashMap<u32, String> {
         &self.vocab
     }
+
+    /// Splits the text with GPT-2's pattern and maps the bytes of every word to
+    /// the characters BPE works on, so any UTF-8 input can be tokenized.
+    /// Every word comes with the byte offset it starts at.
+    fn pre_tokenize(text: &str) -> Vec<(usize, String)> {
+        let mut words = Vec::new();
+        let mut end = 0;
+        for word in gpt2_pattern().find_iter(text) {
+            match word {
+                Ok(word) => {
+                    words.push((word.start(), word.as_str()));
+                    end = word.end();
+                }
+                Err(e) => {
+                    warn!("Failed to split text into words: {e}");
+                    break;
+                }
+            }
+        }
+        // The pattern matches everything, so this is only left over if matching failed
+        if end < text.len() {
+            words.push((end, &text[end..]));
+        }
+
+        let byte_chars = bytes_to_unicode();
+        words
+            .into_iter()
+            .map(|(start, word)| {
+                let word = word.bytes().map(|byte| byte_chars[byte as usize]).collect();
+                (start, word)
+            })
+            .collect()
+    }
+
+    /// Applies the merges to a single word, returning the pieces it ends up split into.
+    /// Words that were split before are looked up in the cache.
+    fn bpe(&self, word: &str) -> Vec<String> {
+        if let Some(pieces) = self.bpe_cache.get(word) {
+            return pieces;
+        }
+        let pieces = self.merge(word);
+        self.bpe_cache.insert(word, &pieces);
+        pieces
+    }
+
+    /// Splits the word into characters and merges them back together, highest priority first
+    fn merge(&self, word: &str) -> Vec<String> {
+        let mut pieces: Vec<String> = word.chars().map(String::from).collect();
+        loop {
+            // Find the adjacent pair with the highest priority merge
+            let best = pieces
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    self.merge_ranks
+                        .get(&(pair[0].clone(), pair[1].clone()))
+                        .map(|rank| (*rank, i))
+                })
+                .min();
+            let Some((_, i)) = best else {
+                break;
+            };
+            let (left, right) = (pieces[i].clone(), pieces[i + 1].clone());
+
+            // Merge every occurrence of the pair, left to right
+            let mut merged = Vec::with_capacity(pieces.len());
+            let mut j = 0;
+            while j < pieces.len() {
+                if j + 1 < pieces.len() && pieces[j] == left && pieces[j + 1] == right {
+                    merged.push(format!("{left}{right}"));
+                    j += 2;
+                } else {
+                    merged.push(pieces[j].clone());
+                    j += 1;
+                }
+            }
+            pieces = merged;
+        }
+        pieces
+    }
+}
+
+impl Tokenizer for WordPieceTokenizer {
+    fn get_piece(&self, token: Token) -> Option<&str> {
+        self.vocab.get(&token.id).map(String::as_str)
+    }
+
+    fn get_token(&self, piece: &str) -> Option<Token> {
+        self.token_ids.get(piece).map(|id| Token { id: *id })
+    }
+
+    fn get_special_tokens(&self) -> &SpecialTokens {
+        &self.special_tokens
+    }
+
+    fn is_special(&self, token: Token) -> bool {
+        self.special_ids.contains(&token.id)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.next_id as usize
+    }
+
+    fn add_tokens(&mut self, tokens: &[AddedToken]) -> usize {
+        let mut new_tokens = 0;
+        for added_token in tokens.iter().filter(|token| !token.content.is_empty()) {
+            let id = match self.token_ids.get(&added_token.content) {
+                Some(id) => *id,
+                None => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    self.vocab.insert(id, added_token.content.clone());
+                    self.token_ids.insert(added_token.content.clone(), id);
+                    new_tokens += 1;
+                    id
+                }
+            };
+            if added_token.special {
+                self.special_ids.insert(id);
+            }
+            self.added_vocab.push(added_token.clone(), Token { id });
+        }
+        debug!("Added {new_tokens} new tokens to the vocab");
+        new_tokens
+    }
+
+    /// Turns tokens back into text by joining their pieces and mapping the characters
+    /// back to the bytes they stand for. Invalid UTF-8 is replaced with `�`.
+    fn decode(
+        &self,
+        tokens: &[Token],
+        skip_special_tokens: bool,
+        clean_up_tokenization_spaces: bool,
+    ) -> Box<str> {
+        let byte_chars = unicode_to_bytes();
+        let mut bytes = Vec::new();
+        for token in tokens {
+            let Some(piece) = self.vocab.get(&token.id) else {
+                warn!("Token {} is missing from the vocab, skipping it", token.id);
+                continue;
+            };
+            if skip_special_tokens && self.is_special(*token) {
+                continue;
+            }
+            for c in piece.chars() {
+                match byte_chars.get(&c) {
+                    Some(byte) => bytes.push(*byte),
+                    // Only tokens added on top of the byte-level vocab hold other characters
+                    None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            }
+        }
+        let text = String::from_utf8_lossy(&bytes);
+        if clean_up_tokenization_spaces {
+            clean_up_tokenization(&text).into()
+        } else {
+            text.into()
+        }
+    }
+
+    /// Pieces missing from the vocab become `<unk>`
+    fn tokenize_with_offsets(&self, text: &str) -> Vec<(Token, TokenOffset)> {
+        self.added_vocab
+            .tokenize_around(text, |text| self.tokenize_bpe(text))
+    }
+}
+
+impl WordPieceTokenizer {
+    /// Splits text that holds no added tokens with byte-level BPE
+    fn tokenize_bpe(&self, text: &str) -> Vec<(Token, TokenOffset)> {
+        let space = bytes_to_unicode()[b' ' as usize];
+        let mut tokens = Vec::new();
+        for (mut start, word) in Self::pre_tokenize(text) {
+            for piece in self.bpe(&word) {
+                // Every character of a piece stands for one byte of the text
+                let end = start + piece.chars().count();
+                let trimmed_start = if piece.starts_with(space) && end - start > 1 {
+                    start + 1
+                } else {
+                    start
+                };
+                match self.get_token(&piece).or(self.special_tokens.unk) {
+                    Some(token) => tokens.push((
+                        token,
+                        TokenOffset {
+                            start: trimmed_start,
+                            end,
+                            special: false,
+                        },
+                    )),
+                    None => warn!("{piece:?} and <unk> are missing from the vocab, skipping it"),
+                }
+                start = end;
+            }
+        }
+        tokens
+    }
+}
+
+/// Small tokenizers built in memory, for the tests of every module that needs one
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    /// A `tokenizer.json` with BART's special tokens and the pieces that spell "hi" and " hi"
+    pub const HI_TOKENIZER_JSON: &str = r#"{
+        "added_tokens": [
+            {"id": 0, "content": "<s>", "special": true},
+            {"id": 1, "content": "<pad>", "special": true},
+            {"id": 2, "content": "</s>", "special": true},
+            {"id": 3, "content": "<unk>", "special": true}
+        ],
+        "model": {
+            "type": "BPE",
+            "unk_token": "<unk>",
+            "vocab": {"h": 4, "i": 5, "Ġ": 6, "hi": 7, "Ġhi": 8},
+            "merges": ["h i", ["Ġ", "hi"]]
+        }
+    }"#;
+
+    /// Builds a tokenizer from the contents of a `tokenizer.json`
+    pub fn tokenizer_from_json(config: &str) -> WordPieceTokenizer {
+        WordPieceTokenizer::from_tokenizer_json_str(config).unwrap()
+    }
+
+    /// A tokenizer without merges whose vocab is the special tokens followed by every byte,
+    /// so every character round-trips through its bytes
+    pub fn byte_tokenizer() -> WordPieceTokenizer {
+        let vocab = SPECIAL_TOKENS
+            .into_iter()
+            .map(String::from)
+            .chain(bytes_to_unicode().iter().map(char::to_string))
+            .enumerate()
+            .map(|(id, token)| (token, id as u32))
+            .collect();
+        WordPieceTokenizer::from_parts(vocab, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{byte_tokenizer, tokenizer_from_json, HI_TOKENIZER_JSON};
+    use super::*;
+
+    #[test]
+    fn applies_merges_in_order() {
+        let vocab = [
+            "l", "o", "w", "e", "r", "Ġ", "lo", "low", "er", "Ġlow", "Ġlower", "<unk>",
+        ];
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let merges = [
+            ("l", "o"),
+            ("lo", "w"),
+            ("e", "r"),
+            ("Ġ", "low"),
+            ("Ġlow", "er"),
+        ]
+        .map(|(left, right)| (left.to_owned(), right.to_owned()))
+        .to_vec();
+        let tokenizer = WordPieceTokenizer::from_parts(vocab, merges);
+
+        assert_eq!(
+            WordPieceTokenizer::pre_tokenize("lower lowly"),
+            [(0, "lower".into()), (5, "Ġlowly".into())]
+        );
+        assert_eq!(tokenizer.bpe("lower"), ["low", "er"]);
+        let ids: Vec<u32> = tokenizer
+            .tokenize("lower lower x")
+            .iter()
+            .map(Token::get_id)
+            .collect();
+        // "x" isn't in the vocab, so it becomes <unk>
+        assert_eq!(ids, [7, 8, 10, 5, 11]);
+    }
+
+    #[test]
+    fn pre_tokenizes_like_gpt2() {
+        let words: Vec<String> = WordPieceTokenizer::pre_tokenize("Hello world\n\tdon't")
+            .into_iter()
+            .map(|(_, word)| word)
+            .collect();
+        assert_eq!(words, ["Hello", "Ġworld", "Ċ", "ĉ", "don", "'t"]);
+
+        // Multi-byte characters are split into bytes and can be restored from them
+        let text = "naïve 日本語 🙂";
+        let words = WordPieceTokenizer::pre_tokenize(text);
+        let byte_chars = bytes_to_unicode();
+        let bytes: Vec<u8> = words
+            .into_iter()
+            .flat_map(|(_, word)| word.chars().collect::<Vec<_>>())
+            .map(|c| byte_chars.iter().position(|b| *b == c).unwrap() as u8)
+            .collect();
+        assert_eq!(bytes, text.as_bytes());
+    }
+
+    #[test]
+    fn decodes_tokens() {
+        let tokenizer = byte_tokenizer();
+        let [bos, eos] = [0, 1].map(|id| Token { id });
+
+        let text = "Héllo , wörld 🙂 . I 'm here\n";
+        let mut tokens = vec![bos];
+        tokens.extend(tokenizer.tokenize(text));
+        tokens.push(eos);
+        assert_eq!(&*tokenizer.decode(&tokens, true, false), text);
+        assert_eq!(
+            &*tokenizer.decode(&tokens, false, false),
+            format!("<s>{text}</s>")
+        );
+        assert_eq!(
+            &*tokenizer.decode(&tokens, true, true),
+            "Héllo, wörld 🙂. I'm here\n"
+        );
+        assert_eq!(
+            tokenizer
+                .decode_batch(&[&tokens[..2], &tokens[2..3]], true, true)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn maps_tokens_to_offsets() {
+        // "é" is stored as the two bytes "Ã©"
+        let vocab = ["a", "b", "Ġ", "Ã", "©", "ab", "Ġab"];
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let merges = [("a", "b"), ("Ġ", "ab")]
+            .map(|(left, right)| (left.to_owned(), right.to_owned()))
+            .to_vec();
+        let tokenizer = WordPieceTokenizer::from_parts(vocab, merges);
+
+        let text = "ab ab é";
+        let offsets: Vec<(usize, usize)> = tokenizer
+            .tokenize_with_offsets(text)
+            .iter()
+            .map(|(_, offset)| (offset.start, offset.end))
+            .collect();
+        // The offsets of "Ġab" leave out its space, and "é" is split into its bytes
+        assert_eq!(offsets, [(0, 2), (3, 5), (5, 6), (6, 7), (7, 8)]);
+        assert_eq!(&text[3..5], "ab");
+    }
+
+    #[test]
+    fn loads_tokenizer_json() {
+        let config = HI_TOKENIZER_JSON;
+        let tokenizer = tokenizer_from_json(config);
+        assert_eq!(tokenizer.merge_ranks.len(), 2);
+        let ids: Vec<u32> = tokenizer
+            .tokenize("hi ih hx")
+            .iter()
+            .map(Token::get_id)
+            .collect();
+        // "x" isn't in the vocab
+        assert_eq!(ids, [7, 6, 5, 4, 6, 4, 3]);
+
+        let mut tokens = tokenizer.tokenize("hi ih");
+        tokens.insert(0, Token { id: 0 });
+        assert_eq!(&*tokenizer.decode(&tokens, true, false), "hi ih");
+
+        // Only files that split and format text the way BART's tokenizer does are accepted
+        let load = |pre_tokenizer, post_processor| {
+            let mut config: serde_json::Value = serde_json::from_str(config).unwrap();
+            config["pre_tokenizer"] = pre_tokenizer;
+            config["post_processor"] = post_processor;
+            WordPieceTokenizer::from_tokenizer_json_str(&config.to_string())
+        };
+        let byte_level = serde_json::json!({"type": "ByteLevel", "add_prefix_space": false});
+        let roberta = serde_json::json!({"type": "RobertaProcessing", "add_prefix_space": false});
+        assert!(load(byte_level.clone(), roberta.clone()).is_ok());
+        let whitespace = serde_json::json!({"type": "Whitespace"});
+        assert!(matches!(
+            load(whitespace, roberta),
+            Err(BartError::Tokenizer(_))
+        ));
+        let template = serde_json::json!({"type": "TemplateProcessing"});
+        assert!(matches!(
+            load(byte_level, template),
+            Err(BartError::Tokenizer(_))
+        ));
+    }
+
+    #[test]
+    fn matches_added_tokens() {
+        let mut added_vocab = AddedVocab::default();
+        let added_tokens = [
+            AddedToken::new("<sep>"),
+            AddedToken {
+                single_word: true,
+                ..AddedToken::new("<title>")
+            },
+            AddedToken {
+                lstrip: true,
+                rstrip: true,
+                ..AddedToken::new("<bullet>")
+            },
+        ];
+        for (id, added_token) in added_tokens.into_iter().enumerate() {
+            added_vocab.push(added_token, Token { id: id as u32 });
+        }
+
+        let ranges: Vec<_> = added_vocab
+            .split("a<sep>b x<title> <title> <bullet> c")
+            .into_iter()
+            .map(|(range, added)| (range, added.map(|(_, token)| token.id)))
+            .collect();
+        // The first "<title>" is inside a word, and the spaces around "<bullet>" are stripped
+        assert_eq!(
+            ranges,
+            [
+                (0..1, None),
+                (1..6, Some(0)),
+                (6..17, None),
+                (17..24, Some(1)),
+                (25..33, Some(2)),
+                (34..35, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn saves_added_tokens() {
+        let mut tokenizer = byte_tokenizer();
+        let added_tokens = [
+            AddedToken::new("<sep>"),
+            AddedToken {
+                lstrip: true,
+                rstrip: true,
+                ..AddedToken::new("<bullet>")
+            },
+        ];
+        assert_eq!(tokenizer.add_tokens(&added_tokens), 2);
+        assert_eq!(tokenizer.add_tokens(&added_tokens[..1]), 0);
+        assert_eq!(tokenizer.vocab_size(), 263);
+
+        // "a" and "b" are the bytes 97 and 98, which come after the special tokens
+        let text = "a<sep>b <bullet> a <mask>";
+        let ids: Vec<u32> = tokenizer.tokenize(text).iter().map(Token::get_id).collect();
+        assert_eq!(ids, [102, 261, 103, 262, 102, 4]);
+
+        let loaded = tokenizer_from_json(&tokenizer.to_tokenizer_json().unwrap());
+        assert_eq!(loaded.tokenize(text), tokenizer.tokenize(text));
+        assert_eq!(loaded.vocab_size(), 263);
+        assert!(!loaded.is_special(Token { id: 261 }));
+        assert!(loaded.is_special(Token { id: 4 }));
+    }
+
+    #[test]
+    fn looks_up_special_tokens() {
+        let config = r#"{
+            "added_tokens": [
+                {"id": 0, "content": "<s>", "special": true},
+                {"id": 1, "content": "<pad>", "special": true},
+                {"id": 2, "content": "</s>", "special": true},
+                {"id": 3, "content": "<unk>", "special": true},
+                {"id": 4, "content": "en_XX", "special": true},
+                {"id": 5, "content": "ro_RO", "special": true},
+                {"id": 6, "content": "<mask>", "special": true}
+            ],
+            "model": {"vocab": {"ab_CD": 7}, "merges": []}
+        }"#;
+        let tokenizer = tokenizer_from_json(config);
+        let special_tokens = tokenizer.get_special_tokens();
+        let ids = [
+            special_tokens.bos,
+            special_tokens.pad,
+            special_tokens.eos,
+            special_tokens.unk,
+            special_tokens.mask,
+        ]
+        .map(|token| token.unwrap().get_id());
+        assert_eq!(ids, [0, 1, 2, 3, 6]);
+        // "ab_CD" looks like a language code, but isn't special
+        assert_eq!(special_tokens.lang_codes.len(), 2);
+        assert_eq!(special_tokens.lang_codes["ro_RO"].get_id(), 5);
+        assert_eq!(tokenizer.get_token("ab_CD").unwrap().get_id(), 7);
+        assert!(!tokenizer.is_special(Token { id: 7 }));
+    }
+
+    #[test]
+    fn matches_bart_tokenizer() {
+        let tokenizer = WordPieceTokenizer::new("bart-large-cnn/vocab.json").unwrap();
+        // `BartTokenizer("Hello, my dog is cute")` without `<s>` and `</s>`
+        let ids: Vec<u32> = tokenizer
+            .tokenize("Hello, my dog is cute")
+            .iter()
+            .map(Token::get_id)
+            .collect();
+        assert_eq!(ids, [31414, 6, 127, 2335, 16, 11962]);
+
+        // Contractions, newlines and multi-byte characters are split like GPT-2 splits them
+        let pieces: Vec<&str> = tokenizer
+            .tokenize("It's 5 €, isn't it?\nYes.")
+            .into_iter()
+            .map(|token| tokenizer.get_piece(token).unwrap())
+            .collect();
+        assert_eq!(
+            pieces,
+            ["It", "'s", "Ġ5", "ĠâĤ¬", ",", "Ġisn", "'t", "Ġit", "?", "Ċ", "Yes", "."]
+        );
+    }
 }
@@ -41,7 +41,7 @@ pub mod assertions {
                     return Ok(false);
                 }
             } else {
-                if tensors_equal(&a_elem, &b_elem)? == false {
+                if !tensors_equal(&a_elem, &b_elem)? {
                     return Ok(false);
                 }
             }